@@ -17,6 +17,45 @@ end
 --   local sqlite = orm().sqlite():db('test'):find('id', 'name') -- sqlite 需要给出对应的字段
 -- end
 
+-- mysql事务，回调函数正常返回时提交，出错时回滚
+-- function _M.transfer(request)
+--   return MYSQL:transaction(function(tx)
+--     tx:exec_drop('UPDATE account SET balance = balance - ? WHERE id = ?', 100, 1)
+--     tx:savepoint('transfer')
+--     tx:exec_drop('UPDATE account SET balance = balance + ? WHERE id = ?', 100, 2)
+--     return 'ok'
+--   end, { isolation = 'repeatable read' })
+-- end
+
+-- 逐批读取大量数据，读完或者break之后归还连接，lua5.4可以直接在for循环中使用
+-- function _M.export(request)
+--   local count = 0
+--   for rows in MYSQL:stream('SELECT * FROM orders WHERE status = :status', { status = 1 }, { batch = 500 }) do
+--     count = count + #rows
+--   end
+--   return count
+-- end
+
+-- lua5.1、luajit和luau不能在for循环中调用异步方法，需要在while循环中调用stream:next()，break之前调用stream:close()
+-- function _M.export(request)
+--   local count = 0
+--   local stream = MYSQL:stream('SELECT * FROM orders WHERE status = :status', { status = 1 }, { batch = 500 })
+--   while true do
+--     local rows = stream:next()
+--     if not rows then
+--       break
+--     end
+--     count = count + #rows
+--   end
+--   return count
+-- end
+
+-- postgres的jsonb、数组、uuid、timestamptz和numeric会转换成对应的lua值，numeric默认为string
+-- function _M.pg_orders(request)
+--   return POSTGRES:exec('SELECT id, items, tags FROM orders WHERE id = ANY(:ids) AND meta @> :meta',
+--     { ids = { 1, 2, 3 }, meta = { paid = true } })
+-- end
+
 function _M.get_user_info(request)
   local params = request._request:params()
   valid.require(params, { 'username', 'age' })
@@ -52,4 +91,13 @@ function _M.ws(request)
   return request._request:upgrade(func)
 end
 
+-- websocket客户端，连接本地hive服务的/ws，发送123会收到hello，需要开启ws功能
+function _M.ws_client(request)
+  local client = hive.ws_connect('ws://127.0.0.1:3000/ws', nil, { retries = 3, backoff = 0.5 })
+  client:send(hive.ws_message.text('123'))
+  local msg = client:recv()
+  client:close()
+  return msg and msg:to_text()
+end
+
 return _M
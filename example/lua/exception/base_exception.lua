@@ -1,9 +1,9 @@
 -- local response = require 'response'
 local response = hive.response
 
--- 对错误的处理
-local function base_exception(code, message, status)
-  local data = { code = code, message = message }
+-- 对错误的处理，参数来自hive.web_error(code, message, status, data)
+local function base_exception(code, message, status, data)
+  local resp_data = { code = code, message = message, data = data }
   local resp = response.new():headers({
     ['Content-type'] = 'application/json'
   })
@@ -12,7 +12,7 @@ local function base_exception(code, message, status)
   else
     resp = resp:status(200)
   end
-  return resp:body(data)
+  return resp:body(resp_data)
   -- local resp = '<div>code: ' .. code .. '</div>'
   -- resp = resp .. '<div>message: ' .. message .. '</div>'
   -- return response.html(resp)
@@ -23,6 +23,13 @@ local _type = {
   ['sqlite'] = 2
 }
 
+---mysql写操作的结果，last_insert_id超出整数范围时为字符串
+---@class WriteResult
+---@field affected_rows integer 影响的行数，批量执行时为所有语句的总和
+---@field last_insert_id integer|string|nil 没有AUTO_INCREMENT字段时为nil
+---@field warnings integer
+---@field info string
+
 ---数组合并
 ---@param t1 table
 ---@param t2 table
@@ -40,10 +47,8 @@ end
 function orm.new(user, pass, host, database)
   if database ~= nil then
     orm._database = database
-    MYSQL = hive.mysql.new(user or MYSQL_USER, pass or MYSQL_PASS, host or MYSQL_HOST, database)
-  else
-    MYSQL = hive.mysql.new(user or MYSQL_USER, pass or MYSQL_PASS, host or MYSQL_HOST)
   end
+  MYSQL = hive.mysql.new(user or MYSQL_USER, pass or MYSQL_PASS, host or MYSQL_HOST, database, MYSQL_OPTIONS)
   -- orm._type = 'mysql'
   return orm
 end
@@ -578,9 +583,10 @@ end
 ---批量执行原始sql
 ---@param sql string
 ---@param params table | nil
+---@return WriteResult|nil
 function orm:exec_batch(sql, params)
   if self._type == 'mysql' then
-    MYSQL:exec_batch(sql, params)
+    return MYSQL:exec_batch(sql, params)
   elseif self._type == 'sqlite' then
     SQLITE:execute_batch(sql)
   end
@@ -638,6 +644,9 @@ function orm:find_all(...)
   return data
 end
 
+---插入一行数据
+---@param data table
+---@return WriteResult|any mysql返回写操作结果，可以从last_insert_id取得新的id
 function orm:insert(data)
   local sql = 'INSERT INTO '
   local values = 'VALUES('
@@ -659,7 +668,7 @@ function orm:insert(data)
   sql    = sub(sql, 1, -2) .. ')'
   values = sub(values, 1, -2) .. ')'
   if self._type == _type.mysql then
-    return MYSQL:exec(sql .. values, params)
+    return MYSQL:exec_drop(sql .. values, params)
   elseif self._type == _type.sqlite then
     return SQLITE:insert(sql .. values, params)
   end
@@ -668,6 +677,7 @@ end
 ---批量插入
 ---@param fields table
 ---@param data table|nil
+---@return WriteResult
 function orm:batch_insert(fields, data)
   local sql = 'INSERT INTO '
   local values = 'VALUES('
@@ -719,9 +729,12 @@ function orm:batch_insert(fields, data)
   values = sub(values, 1, -2)
   sql = sql .. ')'
   values = values .. ')'
-  MYSQL:exec_batch(sql .. values, params)
+  return MYSQL:exec_batch(sql .. values, params)
 end
 
+---有条件并且能查到数据时更新，否则插入
+---@param data table
+---@return WriteResult|any
 function orm:save(data)
   if self._wheres ~= nil then
     local res = self:find()
@@ -735,6 +748,8 @@ function orm:save(data)
   end
 end
 
+---@param data table
+---@return WriteResult|any mysql返回写操作结果，affected_rows为0表示没有更新任何行
 function orm:update(data)
   local sql
   if self._database ~= '' then
@@ -766,12 +781,15 @@ function orm:update(data)
   end
   params = array_merge(params, self._params)
   if self._type == _type.mysql then
-    return MYSQL:exec(sql, params)
+    return MYSQL:exec_drop(sql, params)
   elseif self._type == _type.sqlite then
     return SQLITE:execute(sql, params)
   end
 end
 
+---字段的值直接拼接到sql中
+---@param data table
+---@return WriteResult|any
 function orm:raw_update(data)
   local sql
   if self._database ~= '' then
@@ -792,12 +810,15 @@ function orm:raw_update(data)
   local params = {}
   params = array_merge(params, self._params)
   if self._type == _type.mysql then
-    return MYSQL:exec(sql, params)
+    return MYSQL:exec_drop(sql, params)
   elseif self._type == _type.sqlite then
     return SQLITE:execute(sql, params)
   end
 end
 
+---开启SOFT_DELETE时只设置删除时间
+---@param datetime integer|nil
+---@return WriteResult|any
 function orm:delete(datetime)
   local sql
   if SOFT_DELETE ~= nil and SOFT_DELETE then
@@ -822,7 +843,7 @@ function orm:delete(datetime)
   end
   params = array_merge(params, self._params)
   if self._type == _type.mysql then
-    return MYSQL:exec(sql, params)
+    return MYSQL:exec_drop(sql, params)
   elseif self._type == _type.sqlite then
     return SQLITE:execute(sql, params)
   end
@@ -12,12 +12,9 @@ function response.json(data, is_object)
       body = hive.empty_array()
     end
   else
-    -- body = hive.to_json(data)
     body = data
   end
-  return hive_response.new():status(200):headers({
-    ['Content-type'] = 'application/json'
-  }):body(body)
+  return hive_response.json(body)
 end
 
 function response.success(data, code, message)
@@ -52,35 +49,15 @@ function response.fail(code, message, data)
 end
 
 function response.html(body)
-  return hive_response.new():headers({
-    ['Content-type'] = 'text/html'
-  }):status(200):body(body)
+  return hive_response.html(body)
 end
 
 function response.pay_success()
-  return hive_response.new():status(200):headers({
-    ['Content-type'] = 'text/plain'
-  }):body('success')
-  -- return {
-  --   ['status'] = 200,
-  --   ['headers'] = {
-  --     ['Content-type'] = 'text/plain'
-  --   },
-  --   ['body'] = 'success'
-  -- }
+  return hive_response.text('success')
 end
 
 function response.pay_fail()
-  return hive_response.new():status(200):headers({
-    ['Content-type'] = 'text/plain'
-  }):body('')
-  -- return {
-  --   ['status'] = 200,
-  --   ['headers'] = {
-  --     ['Content-type'] = 'text/plain'
-  --   },
-  --   ['body'] = ''
-  -- }
+  return hive_response.text('')
 end
 
 return response
@@ -9,9 +9,11 @@ local router = r.new()
 router:match('get', '/', test.index)
 -- match第四个参数是传中间件函数
 -- router:match('GET', '/test', test.index, auth_token)
+-- 第五个参数是路由选项，例如：router:match('get', '/list', test.list, nil, { etag = true })
 router:match('get', '/get_user_info', test.get_user_info)
 router:match('get', '/test', test.test)
 -- router:match('get', '/template', test.template)
 router:match('get', '/ws', test.ws)
+router:match('get', '/ws_client', test.ws_client)
 
 return router
@@ -5,8 +5,9 @@ function router.new()
   return router
 end
 
-function router:match(method, path, func, middleware)
-  self.r:match(method, path, func, middleware)
+-- options是路由选项，例如：{ etag = true }
+function router:match(method, path, func, middleware, options)
+  self.r:match(method, path, func, middleware, options)
 end
 
 function router:execute(method, path)
@@ -4,6 +4,8 @@ use std::fmt;
 use downloader::Error as DownloaderError;
 #[cfg(feature = "hive_log")]
 use fast_log::error::LogError;
+#[cfg(feature = "lua_hotfix")]
+use globset::Error as GlobError;
 use hyper::{Body, Error as HyperError};
 
 use http::{Error as HttpError, Response};
@@ -20,7 +22,7 @@ use mlua::prelude::{Lua, LuaError as MLuaError, LuaFunction, LuaResult};
 use multer::Error as MulterError;
 #[cfg(feature = "lua_hotfix")]
 use notify::Error as NotifyError;
-use serde_json::Error as JsonError;
+use serde_json::{Error as JsonError, Value as JsonValue};
 use std::io::Error as IoError;
 use std::net::AddrParseError;
 use std::path::StripPrefixError;
@@ -30,10 +32,14 @@ use v8::DataError as V8DataError;
 #[cfg(feature = "create_object")]
 use zip::result::ZipError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Error {
     pub code: u16,
     pub message: String,
+    // 返回给客户端的http状态码
+    pub status: Option<u16>,
+    // 附带的额外数据，会原样传给异常处理函数
+    pub data: Option<JsonValue>,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -48,12 +54,16 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
     feature = "luajit52"
 ))]
 pub fn create_error(lua: &Lua) -> LuaResult<LuaFunction> {
+    use mlua::prelude::{LuaSerdeExt, LuaValue};
     use std::sync::Arc;
-    lua.create_function(|_, (code, message): (u16, String)| {
-        log::error!("{message}");
-        let err: Error = Error::new(code, message);
-        Err::<(), MLuaError>(MLuaError::ExternalError(Arc::new(err)))
-    })
+    lua.create_function(
+        |lua, (code, message, status, data): (u16, String, Option<u16>, Option<LuaValue>)| {
+            let mut err: Error = Error::new(code, message);
+            err.status = status;
+            err.data = data.map(|v| lua.from_value(v)).transpose()?;
+            Err::<(), MLuaError>(MLuaError::ExternalError(Arc::new(err)))
+        },
+    )
 }
 
 impl Error {
@@ -63,7 +73,12 @@ impl Error {
     {
         let message: String = message.into();
         log::error!("{}", message);
-        Self { code, message }
+        Self {
+            code,
+            message,
+            status: None,
+            data: None,
+        }
     }
 
     pub(crate) fn parse_params(error: serde_urlencoded::de::Error) -> Self {
@@ -71,6 +86,26 @@ impl Error {
         Self {
             code: 3000u16,
             message: error.to_string(),
+            status: None,
+            data: None,
+        }
+    }
+
+    // 从lua错误中找回hive.web_error抛出的Error，包括被CallbackError包裹的情况
+    #[cfg(any(
+        feature = "lua51",
+        feature = "lua52",
+        feature = "lua53",
+        feature = "lua54",
+        feature = "luau",
+        feature = "luajit",
+        feature = "luajit52"
+    ))]
+    pub fn from_lua_error(err: &MLuaError) -> Option<Self> {
+        match err {
+            MLuaError::ExternalError(v) => v.downcast_ref::<Error>().cloned(),
+            MLuaError::CallbackError { cause, .. } => Self::from_lua_error(cause),
+            _ => None,
         }
     }
 
@@ -114,7 +149,7 @@ impl std::error::Error for Error {}
 ))]
 impl From<MLuaError> for Error {
     fn from(value: MLuaError) -> Self {
-        Self::new(2000, value.to_string())
+        Self::from_lua_error(&value).unwrap_or_else(|| Self::new(2000, value.to_string()))
     }
 }
 
@@ -144,6 +179,13 @@ impl From<NotifyError> for Error {
     }
 }
 
+#[cfg(feature = "lua_hotfix")]
+impl From<GlobError> for Error {
+    fn from(value: GlobError) -> Self {
+        Self::new(2009, value.to_string())
+    }
+}
+
 #[cfg(feature = "create_object")]
 impl From<DownloaderError> for Error {
     fn from(value: DownloaderError) -> Self {
@@ -0,0 +1,311 @@
+use http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    HeaderMap, HeaderValue, Method, Response, StatusCode,
+};
+use hyper::Body;
+use mlua::prelude::*;
+use regex::Regex;
+
+enum AllowOrigin {
+    Any,
+    List(Vec<String>),
+    Regex(Regex),
+    Func(LuaRegistryKey),
+}
+
+// 跨域设置，例如：
+// hive.server():cors({ origins = { 'https://a.com' }, credentials = true, max_age = 3600 })
+// origins可以是'*'、数组或者函数function(origin) return true end，也可以用origin_regex设置正则
+pub struct Cors {
+    allow_origin: AllowOrigin,
+    allow_methods: HeaderValue,
+    // 为None时，原样返回Access-Control-Request-Headers
+    allow_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+    expose_headers: Option<HeaderValue>,
+}
+
+fn join_header_value(values: Vec<String>) -> LuaResult<HeaderValue> {
+    HeaderValue::from_str(&values.join(", ")).to_lua_err()
+}
+
+impl Cors {
+    pub fn new(lua: &Lua, options: LuaTable) -> LuaResult<Self> {
+        let origin_regex: Option<String> = options.get("origin_regex")?;
+        let allow_origin = if let Some(re) = origin_regex {
+            // 正则需要匹配完整的origin，否则https://a\.com也会允许https://a.com.evil.com
+            AllowOrigin::Regex(Regex::new(&format!("^(?:{re})$")).to_lua_err()?)
+        } else {
+            match options.get::<_, LuaValue>("origins")? {
+                LuaValue::Nil => AllowOrigin::Any,
+                LuaValue::String(v) if v.as_bytes() == b"*" => AllowOrigin::Any,
+                LuaValue::String(v) => AllowOrigin::List(vec![v.to_str()?.to_string()]),
+                LuaValue::Table(v) => AllowOrigin::List(
+                    v.sequence_values::<String>()
+                        .collect::<LuaResult<Vec<String>>>()?,
+                ),
+                LuaValue::Function(f) => AllowOrigin::Func(lua.create_registry_value(f)?),
+                v => {
+                    return Err(LuaError::FromLuaConversionError {
+                        from: v.type_name(),
+                        to: "origins",
+                        message: Some("origins must be '*', a table or a function".to_string()),
+                    })
+                }
+            }
+        };
+        let methods: Option<Vec<String>> = options.get("methods")?;
+        let methods = methods.unwrap_or_else(|| {
+            ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"]
+                .iter()
+                .map(|v| v.to_string())
+                .collect()
+        });
+        let headers: Option<Vec<String>> = options.get("headers")?;
+        let expose_headers: Option<Vec<String>> = options.get("expose_headers")?;
+        Ok(Cors {
+            allow_origin,
+            allow_methods: join_header_value(methods)?,
+            allow_headers: headers.map(join_header_value).transpose()?,
+            allow_credentials: options
+                .get::<_, Option<bool>>("credentials")?
+                .unwrap_or(false),
+            max_age: options.get("max_age")?,
+            expose_headers: expose_headers.map(join_header_value).transpose()?,
+        })
+    }
+
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(ORIGIN)
+            && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    // 返回Access-Control-Allow-Origin的值，不允许跨域时返回None
+    // 函数出错时当作不允许跨域，并记录错误
+    async fn allowed_origin(&self, lua: &Lua, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str: &str = origin.to_str().ok()?;
+        let allowed: bool = match self.allow_origin {
+            AllowOrigin::Any => {
+                // 携带cookie时不能返回*
+                if !self.allow_credentials {
+                    return Some(HeaderValue::from_static("*"));
+                }
+                true
+            }
+            AllowOrigin::List(ref list) => list.iter().any(|v| v == origin_str),
+            AllowOrigin::Regex(ref re) => re.is_match(origin_str),
+            AllowOrigin::Func(ref key) => {
+                let result = match lua.registry_value::<LuaFunction>(key) {
+                    Ok(func) => func.call_async::<_, bool>(origin_str).await,
+                    Err(err) => Err(err),
+                };
+                result.unwrap_or_else(|err| {
+                    log::error!("cors origins function error: {err}");
+                    false
+                })
+            }
+        };
+        allowed.then(|| origin.clone())
+    }
+
+    // 响应随Origin变化时，不允许跨域的响应也要加上Vary，否则缓存可能把它返回给允许的origin
+    fn vary_origin(&self, headers: &mut HeaderMap) {
+        if !matches!(self.allow_origin, AllowOrigin::Any) || self.allow_credentials {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+
+    fn set_common_headers(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    // 处理OPTIONS预检请求，不经过路由
+    pub async fn preflight(&self, lua: &Lua, req_headers: &HeaderMap) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        self.vary_origin(resp.headers_mut());
+        let origin = match req_headers.get(ORIGIN) {
+            Some(origin) => self.allowed_origin(lua, origin).await,
+            None => None,
+        };
+        if let Some(origin) = origin {
+            let headers = resp.headers_mut();
+            self.set_common_headers(headers, origin);
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, self.allow_methods.clone());
+            // 没有设置headers时原样返回请求的headers，响应随请求头变化
+            let allow_headers = match self.allow_headers {
+                Some(ref allow_headers) => Some(allow_headers.clone()),
+                None => {
+                    headers.append(
+                        VARY,
+                        HeaderValue::from_static("Access-Control-Request-Headers"),
+                    );
+                    req_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+                }
+            };
+            if let Some(allow_headers) = allow_headers {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+            if let Some(max_age) = self.max_age {
+                headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+            }
+        }
+        resp
+    }
+
+    // 给普通请求的响应加上跨域头
+    pub async fn apply(
+        &self,
+        lua: &Lua,
+        origin: Option<HeaderValue>,
+        mut resp: Response<Body>,
+    ) -> Response<Body> {
+        self.vary_origin(resp.headers_mut());
+        let origin = match origin {
+            Some(ref origin) => self.allowed_origin(lua, origin).await,
+            None => None,
+        };
+        if let Some(origin) = origin {
+            let headers = resp.headers_mut();
+            self.set_common_headers(headers, origin);
+            if let Some(ref expose_headers) = self.expose_headers {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers.clone());
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(lua: &Lua, options: &str) -> Cors {
+        Cors::new(lua, lua.load(options).eval().unwrap()).unwrap()
+    }
+
+    fn request(origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers.insert(
+            ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("PUT"),
+        );
+        headers.insert(
+            ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("x-token"),
+        );
+        headers
+    }
+
+    fn vary(resp: &Response<Body>) -> Vec<&str> {
+        resp.headers()
+            .get_all(VARY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    fn allow_origin(resp: &Response<Body>) -> Option<&str> {
+        resp.headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.to_str().unwrap())
+    }
+
+    async fn apply(lua: &Lua, cors: &Cors, origin: &str) -> Response<Body> {
+        let origin = Some(HeaderValue::from_str(origin).unwrap());
+        cors.apply(lua, origin, Response::new(Body::empty())).await
+    }
+
+    #[tokio::test]
+    async fn any_origin() {
+        let lua = Lua::new();
+        let cors = cors(&lua, "{ max_age = 600 }");
+        let resp = cors.preflight(&lua, &request("https://a.com")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow_origin(&resp), Some("*"));
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(resp.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(vary(&resp), ["Access-Control-Request-Headers"]);
+        let resp = apply(&lua, &cors, "https://a.com").await;
+        assert_eq!(allow_origin(&resp), Some("*"));
+        assert!(vary(&resp).is_empty());
+    }
+
+    #[tokio::test]
+    async fn origin_list() {
+        let lua = Lua::new();
+        let cors = cors(
+            &lua,
+            "{ origins = { 'https://a.com' }, headers = { 'x-token' }, expose_headers = { 'x-id' } }",
+        );
+        let resp = cors.preflight(&lua, &request("https://a.com")).await;
+        assert_eq!(allow_origin(&resp), Some("https://a.com"));
+        assert_eq!(vary(&resp), ["Origin"]);
+        let resp = apply(&lua, &cors, "https://a.com").await;
+        assert_eq!(allow_origin(&resp), Some("https://a.com"));
+        assert_eq!(resp.headers()[ACCESS_CONTROL_EXPOSE_HEADERS], "x-id");
+        // 不允许的origin也要有Vary
+        let resp = cors.preflight(&lua, &request("https://b.com")).await;
+        assert_eq!(allow_origin(&resp), None);
+        assert_eq!(vary(&resp), ["Origin"]);
+        let resp = apply(&lua, &cors, "https://b.com").await;
+        assert_eq!(allow_origin(&resp), None);
+        assert_eq!(vary(&resp), ["Origin"]);
+    }
+
+    #[tokio::test]
+    async fn origin_regex() {
+        let lua = Lua::new();
+        let cors = cors(&lua, r"{ origin_regex = [[https://(\w+\.)?a\.com]] }");
+        for (origin, allowed) in [
+            ("https://a.com", true),
+            ("https://x.a.com", true),
+            ("https://a.com.evil.com", false),
+            ("http://a.com", false),
+        ] {
+            let resp = apply(&lua, &cors, origin).await;
+            assert_eq!(allow_origin(&resp).is_some(), allowed, "{origin}");
+            assert_eq!(vary(&resp), ["Origin"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials() {
+        let lua = Lua::new();
+        let cors = cors(&lua, "{ credentials = true }");
+        let resp = apply(&lua, &cors, "https://a.com").await;
+        // 携带cookie时不能返回*
+        assert_eq!(allow_origin(&resp), Some("https://a.com"));
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(vary(&resp), ["Origin"]);
+    }
+
+    #[tokio::test]
+    async fn origin_function() {
+        let lua = Lua::new();
+        let cors = cors(
+            &lua,
+            "{ origins = function(origin) if origin == 'bad' then error('boom') end return origin == 'https://a.com' end }",
+        );
+        assert!(allow_origin(&apply(&lua, &cors, "https://a.com").await).is_some());
+        assert!(allow_origin(&apply(&lua, &cors, "https://b.com").await).is_none());
+        // 函数出错时不允许跨域，仍然返回响应
+        let resp = cors.preflight(&lua, &request("bad")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow_origin(&resp), None);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::Error as WebError;
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Datelike, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Timelike,
+};
+use mlua::prelude::*;
+use std::sync::Arc;
+
+// 数据库中不带时区的时间按照这个时区转换
+#[derive(Clone, Copy, Debug)]
+pub enum Timezone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Timezone {
+    // local、utc或者+08:00这样的偏移
+    pub fn parse(tz: &str) -> LuaResult<Self> {
+        let invalid = || {
+            LuaError::ExternalError(Arc::new(WebError::new(
+                6020,
+                format!("Invalid timezone: {tz}"),
+            )))
+        };
+        match tz.to_lowercase().as_str() {
+            "local" => return Ok(Timezone::Local),
+            "utc" | "z" => return Ok(Timezone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            _ => {}
+        }
+        let (sign, rest) = match tz.split_at(1) {
+            ("+", rest) => (1, rest),
+            ("-", rest) => (-1, rest),
+            _ => return Err(invalid()),
+        };
+        let digits: String = rest.chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+        let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Timezone::Fixed)
+            .ok_or_else(invalid)
+    }
+
+    // 夏令时切换时有两个时间的取较早的一个
+    pub fn resolve(&self, naive: &NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Timezone::Local => {
+                let dt = Local.from_local_datetime(naive).earliest()?;
+                Some(dt.with_timezone(&dt.offset().fix()))
+            }
+            Timezone::Fixed(offset) => offset.from_local_datetime(naive).single(),
+        }
+    }
+
+    pub fn convert<Tz: TimeZone>(&self, dt: &DateTime<Tz>) -> DateTime<FixedOffset> {
+        match self {
+            Timezone::Local => {
+                let dt = dt.with_timezone(&Local);
+                dt.with_timezone(&dt.offset().fix())
+            }
+            Timezone::Fixed(offset) => dt.with_timezone(offset),
+        }
+    }
+
+    pub fn naive(&self, dt: &DateTime<FixedOffset>) -> NaiveDateTime {
+        match self {
+            Timezone::Local => dt.with_timezone(&Local).naive_local(),
+            Timezone::Fixed(offset) => dt.with_timezone(offset).naive_local(),
+        }
+    }
+}
+
+// 检查strftime格式，格式错误时format会panic
+pub fn check_pattern(pattern: &str) -> LuaResult<()> {
+    if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+        return Err(LuaError::ExternalError(Arc::new(WebError::new(
+            6022,
+            format!("Invalid date format: {pattern}"),
+        ))));
+    }
+    Ok(())
+}
+
+// 带时区的时间，数据库查询的date_format为datetime时返回
+#[derive(Clone, Copy)]
+pub struct HiveDateTime(pub DateTime<FixedOffset>);
+
+impl LuaUserData for HiveDateTime {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_method("timestamp", |_, this, ()| Ok(this.0.timestamp()));
+        _methods.add_method("timestamp_millis", |_, this, ()| {
+            Ok(this.0.timestamp_millis())
+        });
+        _methods.add_method("year", |_, this, ()| Ok(this.0.year()));
+        _methods.add_method("month", |_, this, ()| Ok(this.0.month()));
+        _methods.add_method("day", |_, this, ()| Ok(this.0.day()));
+        _methods.add_method("hour", |_, this, ()| Ok(this.0.hour()));
+        _methods.add_method("minute", |_, this, ()| Ok(this.0.minute()));
+        _methods.add_method("second", |_, this, ()| Ok(this.0.second()));
+        _methods.add_method("microsecond", |_, this, ()| Ok(this.0.nanosecond() / 1000));
+        // 相对于utc的秒数
+        _methods.add_method("offset", |_, this, ()| {
+            Ok(this.0.offset().local_minus_utc())
+        });
+        _methods.add_method("format", |_, this, pattern: String| {
+            check_pattern(&pattern)?;
+            Ok(this.0.format(&pattern).to_string())
+        });
+        _methods.add_method("to_timezone", |_, this, tz: String| {
+            Ok(HiveDateTime(Timezone::parse(&tz)?.convert(&this.0)))
+        });
+        _methods.add_method("to_table", |lua, this, ()| {
+            let table = lua.create_table()?;
+            table.set("year", this.0.year())?;
+            table.set("month", this.0.month())?;
+            table.set("day", this.0.day())?;
+            table.set("hour", this.0.hour())?;
+            table.set("min", this.0.minute())?;
+            table.set("sec", this.0.second())?;
+            table.set("microsecond", this.0.nanosecond() / 1000)?;
+            table.set("offset", this.0.offset().local_minus_utc())?;
+            Ok(table)
+        });
+        _methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(this.0.format("%Y-%m-%d %H:%M:%S%.f%:z").to_string())
+        });
+        _methods.add_meta_function(
+            LuaMetaMethod::Eq,
+            |_, (lhs, rhs): (HiveDateTime, HiveDateTime)| Ok(lhs.0 == rhs.0),
+        );
+        _methods.add_meta_function(
+            LuaMetaMethod::Lt,
+            |_, (lhs, rhs): (HiveDateTime, HiveDateTime)| Ok(lhs.0 < rhs.0),
+        );
+        _methods.add_meta_function(
+            LuaMetaMethod::Le,
+            |_, (lhs, rhs): (HiveDateTime, HiveDateTime)| Ok(lhs.0 <= rhs.0),
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use crate::lua::query_log::{total_millis, QueryRecord};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
+};
+use hyper::Body;
+use mlua::prelude::*;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{json, Value as JsonValue};
+use std::net::SocketAddr;
+
+// 报错位置前后显示的源码行数
+const SOURCE_CONTEXT: usize = 5;
+
+// 匹配traceback中的位置，例如：controllers/test.lua:25: in function 'index'
+static FRAME_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^\s*(\[string ".*?"\]|[^\s:\[][^:]*):(\d+):\s*(.*)$"#).unwrap());
+
+// dev模式下的调试信息，需要在请求交给lua之前取出
+pub struct DebugInfo {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+    pub remote_addr: SocketAddr,
+    pub route: Option<String>,
+    pub route_params: Vec<(String, String)>,
+    // 出错之前执行过的sql
+    pub queries: Vec<QueryRecord>,
+}
+
+impl DebugInfo {
+    pub fn new(req: &Request<Body>, remote_addr: SocketAddr) -> Self {
+        Self {
+            method: req.method().as_str().to_string(),
+            path: req.uri().path().to_string(),
+            query: req.uri().query().map(|v| v.to_string()),
+            headers: req.headers().clone(),
+            remote_addr,
+            route: None,
+            route_params: Vec::new(),
+            queries: Vec::new(),
+        }
+    }
+
+    fn headers_json(&self) -> JsonValue {
+        let mut headers = serde_json::Map::new();
+        for (key, val) in self.headers.iter() {
+            let val = String::from_utf8_lossy(val.as_bytes()).to_string();
+            headers.insert(key.as_str().to_string(), JsonValue::String(val));
+        }
+        JsonValue::Object(headers)
+    }
+
+    fn queries_json(&self) -> JsonValue {
+        let queries: Vec<JsonValue> = self
+            .queries
+            .iter()
+            .map(|q| {
+                json!({
+                    "sql": q.sql,
+                    "params": q.params,
+                    "rows": q.rows,
+                    "time": q.millis,
+                    "error": q.error,
+                })
+            })
+            .collect();
+        JsonValue::Array(queries)
+    }
+
+    fn query_params(&self) -> Vec<(String, String)> {
+        self.query
+            .as_deref()
+            .and_then(|q| serde_urlencoded::from_str::<Vec<(String, String)>>(q).ok())
+            .unwrap_or_default()
+    }
+}
+
+struct Frame {
+    file: String,
+    line: usize,
+    function: String,
+    source: Vec<(usize, String)>,
+}
+
+impl Frame {
+    fn to_json(&self) -> JsonValue {
+        let source: Vec<JsonValue> = self
+            .source
+            .iter()
+            .map(|(n, code)| json!({ "line": n, "code": code }))
+            .collect();
+        json!({
+            "file": self.file,
+            "line": self.line,
+            "function": self.function,
+            "source": source,
+        })
+    }
+}
+
+// 拆分出错误信息和lua traceback
+fn split_error(err: &LuaError) -> (String, String) {
+    match err {
+        LuaError::CallbackError { traceback, cause } => {
+            let (message, _) = split_error(cause);
+            (message, traceback.clone())
+        }
+        LuaError::RuntimeError(v) => match v.split_once("stack traceback:") {
+            Some((message, traceback)) => (
+                message.trim_end().to_string(),
+                format!("stack traceback:{traceback}"),
+            ),
+            None => (v.clone(), String::new()),
+        },
+        _ => (err.to_string(), String::new()),
+    }
+}
+
+// 读取报错位置附近的源码，[C]和[string "..."]之类的位置没有源码
+async fn read_source(file: &str, line: usize) -> Vec<(usize, String)> {
+    if file.starts_with('[') {
+        return Vec::new();
+    }
+    let content = match tokio::fs::read_to_string(file).await {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let start = line.saturating_sub(SOURCE_CONTEXT).max(1);
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, code)| (i + 1, code.to_string()))
+        .skip(start - 1)
+        .take(line + SOURCE_CONTEXT + 1 - start)
+        .collect()
+}
+
+// 没有traceback时，使用错误信息中的位置
+async fn parse_frames(message: &str, traceback: &str) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = Vec::new();
+    let lines: Vec<&str> = if traceback.is_empty() {
+        message.lines().take(1).collect()
+    } else {
+        traceback.lines().skip(1).collect()
+    };
+    for text in lines {
+        if let Some(cap) = FRAME_RE.captures(text) {
+            let file = cap[1].to_string();
+            let line: usize = cap[2].parse().unwrap_or(0);
+            let source = read_source(&file, line).await;
+            frames.push(Frame {
+                file,
+                line,
+                function: cap[3].to_string(),
+                source,
+            });
+        }
+    }
+    frames
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html_table(rows: &[(String, String)]) -> String {
+    let mut html = String::from("<table>");
+    for (key, val) in rows {
+        html += &format!(
+            "<tr><th>{}</th><td>{}</td></tr>",
+            escape_html(key),
+            escape_html(val)
+        );
+    }
+    html += "</table>";
+    html
+}
+
+fn render_html(info: &DebugInfo, message: &str, traceback: &str, frames: &[Frame]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>hive error</title><style>\
+body{font-family:monospace;margin:2em;color:#222}h1{color:#c0392b;white-space:pre-wrap}\
+pre{background:#f6f6f6;padding:1em;overflow:auto}table{border-collapse:collapse}\
+th,td{border:1px solid #ddd;padding:4px 8px;text-align:left;vertical-align:top}\
+.hl{background:#fdd}</style></head><body>",
+    );
+    html += &format!("<h1>{}</h1>", escape_html(message));
+    html += "<h2>Frames</h2>";
+    for frame in frames {
+        html += &format!(
+            "<h3>{}:{} {}</h3><pre>",
+            escape_html(&frame.file),
+            frame.line,
+            escape_html(&frame.function)
+        );
+        for (n, code) in frame.source.iter() {
+            let class = if *n == frame.line {
+                " class=\"hl\""
+            } else {
+                ""
+            };
+            html += &format!("<div{class}>{n:>5} | {}</div>", escape_html(code));
+        }
+        html += "</pre>";
+    }
+    html += &format!("<h2>Traceback</h2><pre>{}</pre>", escape_html(traceback));
+    html += "<h2>Request</h2>";
+    html += &html_table(&[
+        ("method".to_string(), info.method.clone()),
+        ("path".to_string(), info.path.clone()),
+        ("remote address".to_string(), info.remote_addr.to_string()),
+        (
+            "route".to_string(),
+            info.route.clone().unwrap_or_else(|| "-".to_string()),
+        ),
+    ]);
+    html += "<h2>Route Params</h2>";
+    html += &html_table(&info.route_params);
+    html += "<h2>Query Params</h2>";
+    html += &html_table(&info.query_params());
+    html += &format!(
+        "<h2>Queries ({}, {:.3} ms)</h2><table><tr><th>sql</th><th>params</th><th>rows</th><th>time (ms)</th></tr>",
+        info.queries.len(),
+        total_millis(&info.queries)
+    );
+    for query in info.queries.iter() {
+        let rows = match (&query.error, query.rows) {
+            (Some(err), _) => err.clone(),
+            (None, Some(rows)) => rows.to_string(),
+            (None, None) => "-".to_string(),
+        };
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.3}</td></tr>",
+            escape_html(&query.sql),
+            query.params,
+            escape_html(&rows),
+            query.millis
+        );
+    }
+    html += "</table>";
+    html += "<h2>Headers</h2>";
+    let headers: Vec<(String, String)> = info
+        .headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.as_str().to_string(),
+                String::from_utf8_lossy(v.as_bytes()).to_string(),
+            )
+        })
+        .collect();
+    html += &html_table(&headers);
+    html += "</body></html>";
+    html
+}
+
+// 生成调试页面，浏览器访问时返回html，否则返回json
+pub async fn debug_page(info: DebugInfo, err: &LuaError) -> Response<Body> {
+    let (message, traceback) = split_error(err);
+    let frames = parse_frames(&message, &traceback).await;
+    let is_html = info
+        .headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("text/html"))
+        .unwrap_or(false);
+    let (content_type, body) = if is_html {
+        (
+            "text/html; charset=utf-8",
+            render_html(&info, &message, &traceback, &frames),
+        )
+    } else {
+        let frames: Vec<JsonValue> = frames.iter().map(|f| f.to_json()).collect();
+        let route_params: serde_json::Map<String, JsonValue> = info
+            .route_params
+            .iter()
+            .map(|(k, v)| (k.clone(), JsonValue::String(v.clone())))
+            .collect();
+        let query_params: serde_json::Map<String, JsonValue> = info
+            .query_params()
+            .into_iter()
+            .map(|(k, v)| (k, JsonValue::String(v)))
+            .collect();
+        let body = json!({
+            "error": message,
+            "traceback": traceback,
+            "frames": frames,
+            "request": {
+                "method": info.method,
+                "path": info.path,
+                "remote_addr": info.remote_addr.to_string(),
+                "headers": info.headers_json(),
+                "query_params": query_params,
+            },
+            "route": {
+                "path": info.route,
+                "params": route_params,
+            },
+            "queries": info.queries_json(),
+            "db_time": total_millis(&info.queries),
+        });
+        ("application/json", body.to_string())
+    };
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
@@ -0,0 +1,726 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use http::HeaderValue;
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// permessage-deflate (RFC 7692)
+// tungstenite不支持扩展，所以在socket和tungstenite之间解压客户端发来的帧、压缩发给客户端的帧
+
+// 每条压缩消息末尾被去掉的4个字节
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// 写缓冲超过这个大小时等待socket写完
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeflateConfig {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    // 客户端要求的压缩窗口，小于15时只输出不压缩的块
+    server_max_window_bits: Option<u8>,
+}
+
+// 窗口大小必须在8到15之间
+fn window_bits(value: &str) -> Option<u8> {
+    value
+        .parse::<u8>()
+        .ok()
+        .filter(|bits| (8..=15).contains(bits))
+}
+
+// 从Sec-WebSocket-Extensions中选出第一个可以接受的permessage-deflate，返回配置和响应头
+pub fn negotiate(header: Option<&HeaderValue>) -> Option<(DeflateConfig, HeaderValue)> {
+    let header = header?.to_str().ok()?;
+    for offer in header.split(',') {
+        let mut params = offer.split(';').map(|v| v.trim());
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+        let mut config = DeflateConfig::default();
+        let mut accepted = true;
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) => config.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => config.client_no_context_takeover = true,
+                // 客户端使用更小的窗口不影响解压
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(v)) if window_bits(v).is_some() => {}
+                ("server_max_window_bits", Some(v)) if window_bits(v).is_some() => {
+                    config.server_max_window_bits = window_bits(v);
+                }
+                _ => {
+                    accepted = false;
+                    break;
+                }
+            }
+        }
+        if !accepted {
+            continue;
+        }
+        let mut response = String::from("permessage-deflate");
+        if config.server_no_context_takeover {
+            response += "; server_no_context_takeover";
+        }
+        if config.client_no_context_takeover {
+            response += "; client_no_context_takeover";
+        }
+        if let Some(bits) = config.server_max_window_bits {
+            response += &format!("; server_max_window_bits={bits}");
+        }
+        return Some((config, HeaderValue::from_str(&response).ok()?));
+    }
+    None
+}
+
+impl DeflateConfig {
+    // miniz_oxide不能设置窗口大小，不压缩的块不引用之前的数据，适用于任何窗口
+    fn compression(&self) -> Compression {
+        match self.server_max_window_bits {
+            Some(bits) if bits < 15 => Compression::none(),
+            _ => Compression::default(),
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct FrameHead {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+}
+
+// 缓冲区中有完整的帧时返回帧头、帧头长度和数据长度
+// 解析出帧头就检查数据长度，超过limit时直接报错，不再继续缓存数据
+fn parse_frame(buf: &[u8], limit: Option<usize>) -> io::Result<Option<(FrameHead, usize, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut offset) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        n => (n as u64, 2),
+    };
+    let mask = if masked {
+        if buf.len() < offset + 4 {
+            return Ok(None);
+        }
+        let mut key = [0u8; 4];
+        key.copy_from_slice(&buf[offset..offset + 4]);
+        offset += 4;
+        Some(key)
+    } else {
+        None
+    };
+    let len = usize::try_from(len).map_err(|_| invalid_data("websocket frame too large"))?;
+    if limit.map(|max| len > max).unwrap_or(false) {
+        return Err(invalid_data("websocket frame too large"));
+    }
+    if buf.len() < offset + len {
+        return Ok(None);
+    }
+    let head = FrameHead {
+        fin: buf[0] & 0x80 != 0,
+        rsv1: buf[0] & 0x40 != 0,
+        opcode: buf[0] & 0x0f,
+        mask,
+    };
+    Ok(Some((head, offset, len)))
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+fn encode_frame(head: &FrameHead, mut payload: Vec<u8>, out: &mut Vec<u8>) {
+    let mut first = head.opcode;
+    if head.fin {
+        first |= 0x80;
+    }
+    if head.rsv1 {
+        first |= 0x40;
+    }
+    out.push(first);
+    let mask_bit = if head.mask.is_some() { 0x80 } else { 0 };
+    let len = payload.len();
+    if len < 126 {
+        out.push(mask_bit | len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(mask_bit | 126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(mask_bit | 127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    if let Some(mask) = head.mask {
+        out.extend_from_slice(&mask);
+        apply_mask(&mut payload, mask);
+    }
+    out.extend_from_slice(&payload);
+}
+
+fn deflate(compress: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    let mut pos = 0;
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+        let before = compress.total_in();
+        compress
+            .compress_vec(&input[pos..], &mut out, FlushCompress::Sync)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        pos += (compress.total_in() - before) as usize;
+        if pos == input.len() && out.len() < out.capacity() {
+            return Ok(out);
+        }
+    }
+}
+
+// limit为这条消息还可以解压出的字节数
+fn inflate(decompress: &mut Decompress, input: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity((input.len() * 2 + 64).min(limit.max(64)));
+    let mut pos = 0;
+    loop {
+        if out.len() == out.capacity() {
+            if out.len() >= limit {
+                return Err(invalid_data("websocket message too large"));
+            }
+            out.reserve(out.capacity().min(limit - out.len()).max(64));
+        }
+        let before = decompress.total_in();
+        let status = decompress
+            .decompress_vec(&input[pos..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        pos += (decompress.total_in() - before) as usize;
+        let done = pos == input.len() && out.len() < out.capacity();
+        if done || status == Status::StreamEnd || (status == Status::BufError && pos == input.len())
+        {
+            if out.len() > limit {
+                return Err(invalid_data("websocket message too large"));
+            }
+            return Ok(out);
+        }
+    }
+}
+
+struct Deflate {
+    config: DeflateConfig,
+    max_frame_size: Option<usize>,
+    max_message_size: Option<usize>,
+    compress: Compress,
+    decompress: Decompress,
+    // 当前读取的消息是否压缩
+    read_compressed: bool,
+    // 当前读取的消息已经解压出的字节数
+    read_message_size: usize,
+    read_in: Vec<u8>,
+    read_out: Vec<u8>,
+    write_in: Vec<u8>,
+    write_out: Vec<u8>,
+}
+
+impl Deflate {
+    // 客户端发来的帧：解压后去掉rsv1，使用原来的掩码重新编码
+    fn read_frame(&mut self, mut head: FrameHead, mut payload: Vec<u8>) -> io::Result<()> {
+        if head.opcode & 0x08 == 0 {
+            if head.opcode != 0 {
+                self.read_compressed = head.rsv1;
+                self.read_message_size = 0;
+            } else if head.rsv1 {
+                return Err(invalid_data("rsv1 set on continuation frame"));
+            }
+            if self.read_compressed {
+                if let Some(mask) = head.mask {
+                    apply_mask(&mut payload, mask);
+                }
+                if head.fin {
+                    payload.extend_from_slice(&TAIL);
+                }
+                let limit = self
+                    .max_message_size
+                    .map(|max| max.saturating_sub(self.read_message_size))
+                    .unwrap_or(usize::MAX);
+                let data = inflate(&mut self.decompress, &payload, limit)?;
+                self.read_message_size += data.len();
+                if head.fin && self.config.client_no_context_takeover {
+                    self.decompress.reset(false);
+                }
+                head.rsv1 = false;
+                encode_frame(&head, data, &mut self.read_out);
+                return Ok(());
+            }
+        }
+        // 控制帧和不压缩的消息原样转发，rsv1不正确时由tungstenite报错
+        if let Some(mask) = head.mask {
+            apply_mask(&mut payload, mask);
+        }
+        encode_frame(&head, payload, &mut self.read_out);
+        Ok(())
+    }
+
+    // 发给客户端的帧：压缩数据帧并设置rsv1
+    fn write_frame(&mut self, mut head: FrameHead, payload: Vec<u8>) -> io::Result<()> {
+        if head.opcode & 0x08 != 0 {
+            encode_frame(&head, payload, &mut self.write_out);
+            return Ok(());
+        }
+        // 消息的第一帧设置rsv1，后续帧也是压缩数据
+        head.rsv1 = head.opcode != 0;
+        let mut data = deflate(&mut self.compress, &payload)?;
+        if head.fin {
+            if data.ends_with(&TAIL) {
+                data.truncate(data.len() - TAIL.len());
+            }
+            if self.config.server_no_context_takeover {
+                self.compress.reset();
+            }
+        }
+        encode_frame(&head, data, &mut self.write_out);
+        Ok(())
+    }
+
+    // 一帧的长度不能超过max_frame_size，也不能超过max_message_size
+    // 压缩后的数据可能比原数据稍大，max_message_size留出一些余量
+    fn read_limit(&self) -> Option<usize> {
+        let message = self
+            .max_message_size
+            .map(|max| max.saturating_add(max / 1024 + 64));
+        match (self.max_frame_size, message) {
+            (Some(frame), Some(message)) => Some(frame.min(message)),
+            (frame, message) => frame.or(message),
+        }
+    }
+
+    fn process_read(&mut self) -> io::Result<()> {
+        while let Some((head, offset, len)) = parse_frame(&self.read_in, self.read_limit())? {
+            let payload = self.read_in[offset..offset + len].to_vec();
+            self.read_in.drain(..offset + len);
+            self.read_frame(head, payload)?;
+        }
+        Ok(())
+    }
+
+    fn process_write(&mut self) -> io::Result<()> {
+        while let Some((head, offset, len)) = parse_frame(&self.write_in, None)? {
+            let payload = self.write_in[offset..offset + len].to_vec();
+            self.write_in.drain(..offset + len);
+            self.write_frame(head, payload)?;
+        }
+        Ok(())
+    }
+}
+
+// 开启permessage-deflate时处理帧的压缩和解压，否则直接读写socket
+pub struct WSStream<S> {
+    inner: S,
+    deflate: Option<Box<Deflate>>,
+}
+
+impl<S> WSStream<S> {
+    pub fn new(inner: S) -> Self {
+        WSStream {
+            inner,
+            deflate: None,
+        }
+    }
+
+    pub fn with_deflate(
+        inner: S,
+        config: DeflateConfig,
+        max_frame_size: Option<usize>,
+        max_message_size: Option<usize>,
+    ) -> Self {
+        WSStream {
+            inner,
+            deflate: Some(Box::new(Deflate {
+                config,
+                max_frame_size,
+                max_message_size,
+                compress: Compress::new(config.compression(), false),
+                decompress: Decompress::new(false),
+                read_compressed: false,
+                read_message_size: 0,
+                read_in: Vec::new(),
+                read_out: Vec::new(),
+                write_in: Vec::new(),
+                write_out: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> WSStream<S> {
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let deflate = match self.deflate.as_mut() {
+            Some(deflate) => deflate,
+            None => return Poll::Ready(Ok(())),
+        };
+        while !deflate.write_out.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &deflate.write_out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            deflate.write_out.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WSStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let deflate = match this.deflate.as_mut() {
+            Some(deflate) => deflate,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        loop {
+            if !deflate.read_out.is_empty() {
+                let n = deflate.read_out.len().min(buf.remaining());
+                buf.put_slice(&deflate.read_out[..n]);
+                deflate.read_out.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            let mut tmp = [0u8; 8192];
+            let mut read_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            deflate.read_in.extend_from_slice(read_buf.filled());
+            deflate.process_read()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WSStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.deflate.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.poll_write_out(cx)?.is_pending()
+            && this
+                .deflate
+                .as_ref()
+                .map(|d| d.write_out.len() >= WRITE_HIGH_WATER)
+                .unwrap_or(false)
+        {
+            return Poll::Pending;
+        }
+        if let Some(deflate) = this.deflate.as_mut() {
+            deflate.write_in.extend_from_slice(buf);
+            deflate.process_write()?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(header: &str) -> Option<(DeflateConfig, String)> {
+        let header = HeaderValue::from_str(header).unwrap();
+        negotiate(Some(&header)).map(|(config, v)| (config, v.to_str().unwrap().to_string()))
+    }
+
+    fn response(header: &str) -> Option<String> {
+        offer(header).map(|(_, v)| v)
+    }
+
+    fn deflate(config: DeflateConfig, max_message_size: Option<usize>) -> Box<Deflate> {
+        WSStream::with_deflate((), config, None, max_message_size)
+            .deflate
+            .unwrap()
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let head = FrameHead {
+            fin,
+            rsv1: false,
+            opcode,
+            mask,
+        };
+        let mut out = Vec::new();
+        encode_frame(&head, payload.to_vec(), &mut out);
+        out
+    }
+
+    // 解析出所有帧：(fin, rsv1, opcode, 去掉掩码的数据)
+    fn frames(mut buf: &[u8]) -> Vec<(bool, bool, u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while let Some((head, offset, len)) = parse_frame(buf, None).unwrap() {
+            let mut payload = buf[offset..offset + len].to_vec();
+            if let Some(mask) = head.mask {
+                apply_mask(&mut payload, mask);
+            }
+            frames.push((head.fin, head.rsv1, head.opcode, payload));
+            buf = &buf[offset + len..];
+        }
+        assert!(buf.is_empty());
+        frames
+    }
+
+    fn compress(server: &mut Deflate, input: &[u8]) -> Vec<u8> {
+        server.write_in.extend_from_slice(input);
+        server.process_write().unwrap();
+        std::mem::take(&mut server.write_out)
+    }
+
+    // 按客户端发来的帧加上掩码再解压
+    fn decompress(client: &mut Deflate, compressed: &[u8]) -> Vec<(bool, bool, u8, Vec<u8>)> {
+        for (fin, rsv1, opcode, payload) in frames(compressed) {
+            let head = FrameHead {
+                fin,
+                rsv1,
+                opcode,
+                mask: Some([1, 2, 3, 4]),
+            };
+            encode_frame(&head, payload, &mut client.read_in);
+        }
+        client.process_read().unwrap();
+        frames(&std::mem::take(&mut client.read_out))
+    }
+
+    #[test]
+    fn negotiate_offers() {
+        assert!(negotiate(None).is_none());
+        assert!(response("x-webkit-deflate-frame").is_none());
+        assert_eq!(
+            response("permessage-deflate").as_deref(),
+            Some("permessage-deflate")
+        );
+        assert_eq!(
+            response("permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate")
+        );
+        assert_eq!(
+            response("permessage-deflate; client_max_window_bits=\"10\"").as_deref(),
+            Some("permessage-deflate")
+        );
+        // 不能接受的参数跳过这个offer，选择下一个
+        assert_eq!(
+            response("permessage-deflate; client_max_window_bits=16, permessage-deflate; server_no_context_takeover").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert!(response("permessage-deflate; client_max_window_bits=7").is_none());
+        assert!(response("permessage-deflate; unknown").is_none());
+        assert!(response("permessage-deflate; server_no_context_takeover=1").is_none());
+
+        let (config, header) =
+            offer("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+                .unwrap();
+        assert!(config.server_no_context_takeover && config.client_no_context_takeover);
+        assert_eq!(
+            header,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+    }
+
+    #[test]
+    fn negotiate_server_window_bits() {
+        for bits in 8..=15 {
+            let (config, header) = offer(&format!(
+                "permessage-deflate; server_max_window_bits={bits}"
+            ))
+            .unwrap();
+            assert_eq!(config.server_max_window_bits, Some(bits));
+            assert_eq!(
+                header,
+                format!("permessage-deflate; server_max_window_bits={bits}")
+            );
+        }
+        for value in ["7", "16", "abc"] {
+            let header = format!("permessage-deflate; server_max_window_bits={value}");
+            assert!(response(&header).is_none(), "{value}");
+        }
+        assert!(response("permessage-deflate; server_max_window_bits").is_none());
+    }
+
+    #[test]
+    fn parse_frame_length() {
+        assert!(parse_frame(&[0x81], None).unwrap().is_none());
+        let (head, offset, len) = parse_frame(&frame(true, 1, b"hi", None), None)
+            .unwrap()
+            .unwrap();
+        assert!(head.fin && !head.rsv1 && head.opcode == 1 && head.mask.is_none());
+        assert_eq!((offset, len), (2, 2));
+
+        let payload = vec![b'a'; 300];
+        let buf = frame(false, 2, &payload, Some([1, 2, 3, 4]));
+        let (head, offset, len) = parse_frame(&buf, None).unwrap().unwrap();
+        assert!(!head.fin && head.mask == Some([1, 2, 3, 4]));
+        assert_eq!((offset, len), (8, 300));
+        // 帧头或者数据不完整时等待更多数据
+        assert!(parse_frame(&buf[..3], None).unwrap().is_none());
+        assert!(parse_frame(&buf[..6], None).unwrap().is_none());
+        assert!(parse_frame(&buf[..buf.len() - 1], None).unwrap().is_none());
+
+        let payload = vec![b'a'; 70000];
+        let buf = frame(true, 2, &payload, None);
+        let (_, offset, len) = parse_frame(&buf, None).unwrap().unwrap();
+        assert_eq!((offset, len), (10, 70000));
+    }
+
+    #[test]
+    fn parse_frame_limit() {
+        let buf = frame(true, 2, &[0u8; 300], None);
+        assert!(parse_frame(&buf, Some(300)).unwrap().is_some());
+        // 只收到帧头时就检查长度
+        let err = parse_frame(&buf[..4], Some(299)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut huge = vec![0x82, 127];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(parse_frame(&huge, Some(1024)).is_err());
+    }
+
+    #[test]
+    fn single_frame_round_trip() {
+        let mut server = deflate(DeflateConfig::default(), None);
+        let mut client = deflate(DeflateConfig::default(), None);
+        let text = "hello hello hello hello".repeat(10);
+        let input = frame(true, 1, text.as_bytes(), None);
+
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let compressed = compress(&mut server, &input);
+            let output = frames(&compressed);
+            assert!(output[0].1, "rsv1");
+            assert!(output[0].3.len() < text.len());
+            sizes.push(output[0].3.len());
+            assert_eq!(
+                decompress(&mut client, &compressed),
+                vec![(true, false, 1, text.as_bytes().to_vec())]
+            );
+        }
+        // 保留上下文时重复的消息压缩得更小
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[test]
+    fn no_context_takeover_round_trip() {
+        let (config, _) =
+            offer("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+                .unwrap();
+        let mut server = deflate(config, None);
+        let mut client = deflate(config, None);
+        let input = frame(true, 2, &[7u8; 1000], None);
+        let first = compress(&mut server, &input);
+        for _ in 0..2 {
+            let compressed = compress(&mut server, &input);
+            assert_eq!(compressed, first);
+            assert_eq!(
+                decompress(&mut client, &compressed),
+                vec![(true, false, 2, vec![7u8; 1000])]
+            );
+        }
+    }
+
+    #[test]
+    fn small_window_round_trip() {
+        let (config, _) = offer("permessage-deflate; server_max_window_bits=9").unwrap();
+        let mut server = deflate(config, None);
+        let mut client = deflate(config, None);
+        let text = "abcdefgh".repeat(200);
+        let compressed = compress(&mut server, &frame(true, 1, text.as_bytes(), None));
+        // 不压缩的块中包含原始数据
+        let payload = &frames(&compressed)[0].3;
+        assert!(payload.windows(text.len()).any(|w| w == text.as_bytes()));
+        assert_eq!(
+            decompress(&mut client, &compressed),
+            vec![(true, false, 1, text.as_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn fragmented_round_trip() {
+        let mut server = deflate(DeflateConfig::default(), None);
+        let mut client = deflate(DeflateConfig::default(), None);
+        let mut input = frame(false, 1, b"hello ", None);
+        input.extend(frame(false, 0, b"wor", None));
+        // 分片之间的控制帧不压缩
+        input.extend(frame(true, 9, b"ping", None));
+        input.extend(frame(true, 0, b"ld", None));
+
+        let compressed = compress(&mut server, &input);
+        let output = frames(&compressed);
+        let rsv1: Vec<(bool, u8)> = output.iter().map(|f| (f.1, f.2)).collect();
+        assert_eq!(rsv1, vec![(true, 1), (false, 0), (false, 9), (false, 0)]);
+        assert_eq!(output[2].3, b"ping");
+
+        assert_eq!(
+            decompress(&mut client, &compressed),
+            vec![
+                (false, false, 1, b"hello ".to_vec()),
+                (false, false, 0, b"wor".to_vec()),
+                (true, false, 9, b"ping".to_vec()),
+                (true, false, 0, b"ld".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut server = deflate(DeflateConfig::default(), None);
+        // 解压后超过max_message_size
+        let mut client = deflate(DeflateConfig::default(), Some(100));
+        client.read_in = compress(&mut server, &frame(true, 2, &[0u8; 1000], None));
+        let err = client.process_read().err().unwrap();
+        assert_eq!(err.to_string(), "websocket message too large");
+
+        let mut client = deflate(DeflateConfig::default(), None);
+        client.read_in = frame(false, 1, b"a", None);
+        client.read_in[0] |= 0x40;
+        client.read_in.extend(frame(true, 0, b"b", None));
+        let len = client.read_in.len();
+        client.read_in[len - 3] |= 0x40;
+        let err = client.process_read().err().unwrap();
+        assert_eq!(err.to_string(), "rsv1 set on continuation frame");
+    }
+}
@@ -0,0 +1,106 @@
+use crate::error::Result;
+use http::{
+    header::{CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    HeaderMap, HeaderValue, Method, Response, StatusCode,
+};
+use hyper::{body::HttpBody, Body};
+
+// 单个响应的ETag开关，优先级：响应 > 路由 > 服务器设置
+#[derive(Clone, Copy, Debug)]
+pub struct EtagSetting(pub bool);
+
+// 条件请求用到的请求头，需要在请求交给lua之前取出
+pub struct Conditional {
+    method: Method,
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<HeaderValue>,
+}
+
+// FNV-1a，重新编译之后同样的响应体生成的ETag不变
+fn fnv1a(body: &[u8]) -> u64 {
+    body.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// 根据响应体生成强ETag
+fn strong_etag(body: &[u8]) -> Option<HeaderValue> {
+    let etag = format!("\"{:x}-{:016x}\"", body.len(), fnv1a(body));
+    HeaderValue::from_str(&etag).ok()
+}
+
+// If-None-Match使用弱比较，忽略W/前缀
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|v| v.trim().trim_start_matches("W/") == etag)
+}
+
+impl Conditional {
+    pub fn new(method: &Method, headers: &HeaderMap) -> Self {
+        Self {
+            method: method.clone(),
+            if_none_match: headers.get(IF_NONE_MATCH).cloned(),
+            if_modified_since: headers.get(IF_MODIFIED_SINCE).cloned(),
+        }
+    }
+
+    fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        // 同时存在时，If-None-Match优先，忽略If-Modified-Since
+        if let Some(ref if_none_match) = self.if_none_match {
+            let if_none_match = if_none_match.to_str().unwrap_or_default();
+            return headers
+                .get(ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|etag| etag_matches(if_none_match, etag))
+                .unwrap_or(false);
+        }
+        if let Some(ref if_modified_since) = self.if_modified_since {
+            let since = if_modified_since
+                .to_str()
+                .ok()
+                .and_then(|v| httpdate::parse_http_date(v).ok());
+            let last_modified = headers
+                .get(LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok());
+            if let (Some(since), Some(last_modified)) = (since, last_modified) {
+                return last_modified <= since;
+            }
+        }
+        false
+    }
+
+    // 只处理GET/HEAD的200响应，并且只对长度已知的响应体计算ETag
+    pub async fn apply(self, resp: Response<Body>, enable: bool) -> Result<Response<Body>> {
+        let enable: bool = resp
+            .extensions()
+            .get::<EtagSetting>()
+            .map(|v| v.0)
+            .unwrap_or(enable);
+        if !enable
+            || (self.method != Method::GET && self.method != Method::HEAD)
+            || resp.status() != StatusCode::OK
+        {
+            return Ok(resp);
+        }
+        let mut resp = resp;
+        if !resp.headers().contains_key(ETAG) && resp.body().size_hint().exact().is_some() {
+            let (mut parts, body) = resp.into_parts();
+            let bytes = hyper::body::to_bytes(body).await?;
+            if let Some(etag) = strong_etag(&bytes) {
+                parts.headers.insert(ETAG, etag);
+            }
+            resp = Response::from_parts(parts, Body::from(bytes));
+        }
+        if self.is_not_modified(resp.headers()) {
+            let (mut parts, _) = resp.into_parts();
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove(CONTENT_LENGTH);
+            return Ok(Response::from_parts(parts, Body::empty()));
+        }
+        Ok(resp)
+    }
+}
@@ -3,11 +3,15 @@ use crate::error::{create_error, Result};
 use crate::lua::file_data::FileData;
 #[cfg(feature = "mysql")]
 use crate::lua::mysql_async::create_mysql;
+#[cfg(feature = "postgres")]
+use crate::lua::postgres::create_postgres;
 #[cfg(feature = "ws")]
 use crate::lua::ws::create_message;
+#[cfg(feature = "ws")]
+use crate::lua::ws_client::create_ws_connect;
 use crate::lua::{
-    json::create_empty_array, response::HiveResponseBuilder, router::create_router,
-    server::create_server,
+    json::create_empty_array, query_log::create_query_log, response::HiveResponseBuilder,
+    router::create_router, server::create_server,
 };
 use mlua::prelude::*;
 
@@ -30,6 +34,8 @@ pub fn add_hive_func(
 ) -> Result<LuaTable> {
     let hive: LuaTable = lua.create_table()?;
     hive.set("empty_array", create_empty_array(lua)?)?;
+    // 表示数据库中的NULL，转换成json时为null
+    hive.set("null", lua.null())?;
     #[cfg(feature = "lua_file_data")]
     hive.set("file_data", lua.create_proxy::<FileData>()?)?;
     hive.set("web_error", create_error(lua)?)?;
@@ -40,12 +46,20 @@ pub fn add_hive_func(
     } else {
         hive.set("env", lua.create_table_from([("dev", args_dev)])?)?;
     }
+    // dev模式下查看当前请求执行过的sql和数据库总耗时
+    if args_dev {
+        hive.set("query_log", create_query_log(lua)?)?;
+    }
     hive.set("version", lua.create_string(env!("CARGO_PKG_VERSION"))?)?;
     hive.set("server", create_server(lua)?)?;
     #[cfg(feature = "ws")]
     hive.set("ws_message", create_message(lua)?)?;
+    #[cfg(feature = "ws")]
+    hive.set("ws_connect", create_ws_connect(lua)?)?;
     #[cfg(feature = "mysql")]
     hive.set("mysql", create_mysql(lua)?)?;
+    #[cfg(feature = "postgres")]
+    hive.set("postgres", create_postgres(lua)?)?;
     hive.set("router", create_router(lua)?)?;
     hive.set("response", lua.create_proxy::<HiveResponseBuilder>()?)?;
     Ok(hive)
@@ -1,4 +1,11 @@
-local function update_func(new_func, old_func)
+-- 还没有经过请求验证的修改，第一次请求失败时回滚
+local journal = {}
+
+local function record(tbl, key, old_value)
+  journal[#journal + 1] = { tbl = tbl, key = key, value = old_value }
+end
+
+local function update_func(new_func, old_func, upvalues)
   -- Get upvalues of old function.
   local old_upvalue_map = {}
   for i = 1, math.huge do
@@ -9,26 +16,35 @@ local function update_func(new_func, old_func)
 
   -- Update new upvalues with old.
   for i = 1, math.huge do
-    local name, value = debug.getupvalue(new_func, i)
+    local name = debug.getupvalue(new_func, i)
     if not name then break end
-    print("set up value: ", name)
     local old_value = old_upvalue_map[name]
-    if old_value then
+    if old_value ~= nil then
       debug.setupvalue(new_func, i, old_value)
+      if name ~= '_ENV' then
+        upvalues[name] = true
+      end
     end
   end
 end
 
-local function update_table(new_table, old_table)
+local function update_table(new_table, old_table, upvalues, visited)
+  if visited[old_table] then return end
+  visited[old_table] = true
   -- Compare 2 tables, and update old table.
   for key, value in pairs(new_table) do
     local old_value = old_table[key]
     local type_value = type(value)
     if type_value == "function" and type(old_value) == "function" then
-      update_func(value, old_value)
+      update_func(value, old_value, upvalues)
+      record(old_table, key, old_value)
       old_table[key] = value
     elseif type_value == "table" and type(old_value) == "table" then
-      update_table(value, old_value)
+      update_table(value, old_value, upvalues, visited)
+    elseif old_value == nil then
+      -- 新增的函数和字段
+      record(old_table, key, nil)
+      old_table[key] = value
     end
   end
 
@@ -36,62 +52,79 @@ local function update_table(new_table, old_table)
   local old_meta = debug.getmetatable(old_table)
   local new_meta = debug.getmetatable(new_table)
   if type(old_meta) == "table" and type(new_meta) == "table" then
-    update_table(new_meta, old_meta)
+    update_table(new_meta, old_meta, upvalues, visited)
   end
 end
 
-local function hotfix(filename)
-  if filename == 'route' then
-    local oldModule
-    if package.loaded[filename] then
-      oldModule = package.loaded[filename]
-      package.loaded[filename] = nil
-      local ok, err = pcall(require, filename)
-      if not ok then
-        package.loaded[filename] = oldModule
-        print("reload lua file failed.", err)
-        return
-      end
-    end
-    return
-  end
-  print("start hotfix: ", filename)
-  local oldModule
-  if package.loaded[filename] then
-    oldModule = package.loaded[filename]
-    package.loaded[filename] = nil
-  else
-    print("this file not loaded: ", filename)
-    return
-  end
+-- 重新require，失败时恢复旧模块
+local function require_again(filename)
+  local old_module = package.loaded[filename]
+  package.loaded[filename] = nil
   local ok, err = pcall(require, filename)
   if not ok then
-    package.loaded[filename] = oldModule
-    print("reload lua file failed.", err)
-    return
+    package.loaded[filename] = old_module
+    return nil, err
   end
+  record(package.loaded, filename, old_module)
+  return package.loaded[filename]
+end
 
-  local newModule = package.loaded[filename]
+local hotfix = {}
 
-  if type(newModule) == 'table' and type(oldModule) == 'table' then
-    update_table(newModule, oldModule)
+-- 返回热更新结果：{ module, ok, error, upvalues }
+function hotfix.reload(filename)
+  local report = { module = filename, ok = true, upvalues = {} }
+  local old_module = package.loaded[filename]
+  if old_module == nil then
+    report.ok = false
+    report.error = 'module not loaded'
+    return report
   end
 
-  do
-    if package.loaded['route'] then
-      local _oldModule = package.loaded['route']
-      package.loaded['route'] = nil
-      local _ok, _err = pcall(require, 'route')
-      if not _ok then
-        package.loaded['route'] = _oldModule
-        print("reload lua file failed.", _err)
-        return
-      end
+  local new_module, err = require_again(filename)
+  if new_module == nil then
+    report.ok = false
+    report.error = tostring(err)
+    return report
+  end
+
+  if filename ~= 'route' and type(new_module) == 'table' and type(old_module) == 'table' then
+    -- 原地更新旧模块，引用旧模块的地方不需要重新加载
+    local upvalues = {}
+    update_table(new_module, old_module, upvalues, {})
+    package.loaded[filename] = old_module
+    for name in pairs(upvalues) do
+      report.upvalues[#report.upvalues + 1] = name
+    end
+    table.sort(report.upvalues)
+  elseif filename ~= 'route' and package.loaded['route'] then
+    -- 不能原地更新时，重新加载route使用新模块
+    local _, route_err = require_again('route')
+    if route_err then
+      report.ok = false
+      report.error = tostring(route_err)
     end
   end
+  return report
+end
 
-  print("replaced succeed")
-  -- package.loaded[filename] = oldModule
+-- 第一次请求成功，清空修改记录
+function hotfix.commit()
+  journal = {}
+end
+
+-- 按相反的顺序恢复修改，返回回滚的模块名
+function hotfix.rollback()
+  local modules = {}
+  for i = #journal, 1, -1 do
+    local entry = journal[i]
+    entry.tbl[entry.key] = entry.value
+    if entry.tbl == package.loaded then
+      modules[#modules + 1] = entry.key
+    end
+  end
+  journal = {}
+  return modules
 end
 
 return hotfix
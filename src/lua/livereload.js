@@ -0,0 +1,41 @@
+(function () {
+  var url = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/__hive/livereload';
+
+  function fileName(path) {
+    return path.split('?')[0].split('#')[0].split('/').pop();
+  }
+
+  // 只替换修改过的样式表，找不到时替换全部
+  function swapCss(paths) {
+    var names = paths.map(fileName);
+    var links = Array.prototype.slice.call(document.querySelectorAll('link[rel="stylesheet"][href]'));
+    var matched = links.filter(function (link) {
+      return names.indexOf(fileName(link.getAttribute('href'))) !== -1;
+    });
+    (matched.length ? matched : links).forEach(function (link) {
+      var href = link.getAttribute('href').replace(/([?&])livereload=\d+&?/, '$1').replace(/[?&]$/, '');
+      link.setAttribute('href', href + (href.indexOf('?') === -1 ? '?' : '&') + 'livereload=' + Date.now());
+    });
+  }
+
+  function connect(reconnect) {
+    var ws = new WebSocket(url);
+    ws.onopen = function () {
+      // 服务重启之后刷新页面
+      if (reconnect) location.reload();
+    };
+    ws.onmessage = function (event) {
+      var msg = JSON.parse(event.data);
+      if (msg.type === 'css') {
+        swapCss(msg.paths);
+      } else {
+        location.reload();
+      }
+    };
+    ws.onclose = function () {
+      setTimeout(function () { connect(true); }, 1000);
+    };
+  }
+
+  connect(false);
+})();
@@ -0,0 +1,199 @@
+use crate::error::Result;
+use futures_util::{SinkExt, StreamExt};
+use http::{
+    header::{
+        CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT,
+        SEC_WEBSOCKET_KEY, UPGRADE,
+    },
+    HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::Body;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+// dev模式下浏览器自动刷新，修改页面、js等文件时刷新页面，只修改css时替换样式表
+pub const SCRIPT_PATH: &str = "/__hive/livereload.js";
+pub const SOCKET_PATH: &str = "/__hive/livereload";
+
+const SCRIPT: &str = include_str!("./livereload.js");
+
+static RELOAD: Lazy<broadcast::Sender<String>> = Lazy::new(|| broadcast::channel(16).0);
+
+pub fn is_livereload(path: &str) -> bool {
+    path == SCRIPT_PATH || path == SOCKET_PATH
+}
+
+// 通知所有连接的浏览器，css为修改的样式表，为空时刷新整个页面
+pub fn notify_browsers(css: Vec<String>) {
+    let msg = if css.is_empty() {
+        json!({ "type": "reload" })
+    } else {
+        json!({ "type": "css", "paths": css })
+    };
+    // 没有浏览器连接时发送失败
+    let _ = RELOAD.send(msg.to_string());
+}
+
+fn script() -> Response<Body> {
+    let mut resp = Response::new(Body::from(SCRIPT));
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/javascript; charset=utf-8"),
+    );
+    resp
+}
+
+async fn forward(mut ws: WebSocketStream<hyper::upgrade::Upgraded>) {
+    let mut rx = RELOAD.subscribe();
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    if ws.send(Message::text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+pub fn handle(mut req: Request<Body>) -> Response<Body> {
+    if req.uri().path() == SCRIPT_PATH {
+        return script();
+    }
+    let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            let mut resp = Response::new(Body::from("websocket only"));
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            return resp;
+        }
+    };
+    tokio::task::spawn_local(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                forward(WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await).await
+            }
+            Err(e) => log::error!("livereload upgrade error: {e}"),
+        }
+    });
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = resp.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    if let Ok(key) = HeaderValue::from_str(&key) {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, key);
+    }
+    resp
+}
+
+// 在GET请求返回的html页面的</body>之前插入刷新脚本，压缩过的响应不处理
+pub async fn inject(method: &Method, resp: Response<Body>) -> Result<Response<Body>> {
+    let is_html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    if method != Method::GET
+        || resp.status() != StatusCode::OK
+        || !is_html
+        || resp.headers().contains_key(CONTENT_ENCODING)
+    {
+        return Ok(resp);
+    }
+    let (mut parts, body) = resp.into_parts();
+    let mut html = hyper::body::to_bytes(body).await?.to_vec();
+    let tag = format!("<script src=\"{SCRIPT_PATH}\"></script>");
+    // 页面不一定是utf-8编码，直接在字节中查找
+    let pos = html
+        .windows(7)
+        .rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(html.len());
+    html.splice(pos..pos, tag.bytes());
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(html.len()));
+    Ok(Response::from_parts(parts, Body::from(html)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(status: StatusCode, content_type: &str, body: &'static [u8]) -> Response<Body> {
+        let mut resp = Response::new(Body::from(body));
+        *resp.status_mut() = status;
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        resp.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        resp
+    }
+
+    async fn body(resp: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn inject_before_body() {
+        // 非utf-8的内容原样保留
+        let page: &[u8] = b"<html><BODY>\xc4\xe3\xba\xc3</Body></html>";
+        let resp = html(StatusCode::OK, "text/html; charset=gbk", page);
+        let resp = inject(&Method::GET, resp).await.unwrap();
+        let expected = [
+            &b"<html><BODY>\xc4\xe3\xba\xc3"[..],
+            b"<script src=\"/__hive/livereload.js\"></script>",
+            b"</Body></html>",
+        ]
+        .concat();
+        assert_eq!(resp.headers()[CONTENT_LENGTH], expected.len().to_string());
+        assert_eq!(body(resp).await, expected);
+
+        let resp = html(StatusCode::OK, "text/html", b"<p>hi</p>");
+        let resp = inject(&Method::GET, resp).await.unwrap();
+        assert!(body(resp)
+            .await
+            .ends_with(b"<p>hi</p><script src=\"/__hive/livereload.js\"></script>"));
+    }
+
+    #[tokio::test]
+    async fn skip_other_responses() {
+        let page: &[u8] = b"<html><body></body></html>";
+        let cases = [
+            (Method::HEAD, StatusCode::OK, "text/html"),
+            (Method::POST, StatusCode::OK, "text/html"),
+            (Method::GET, StatusCode::NOT_FOUND, "text/html"),
+            (Method::GET, StatusCode::OK, "application/json"),
+        ];
+        for (method, status, content_type) in cases {
+            let resp = inject(&method, html(status, content_type, page))
+                .await
+                .unwrap();
+            assert_eq!(resp.headers()[CONTENT_LENGTH], page.len().to_string());
+            assert_eq!(body(resp).await, page, "{method} {status} {content_type}");
+        }
+
+        let mut resp = html(StatusCode::OK, "text/html", page);
+        resp.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = inject(&Method::GET, resp).await.unwrap();
+        assert_eq!(body(resp).await, page);
+    }
+}
@@ -1,11 +1,12 @@
 #[cfg(feature = "ws")]
 use crate::error::Error as WebError;
 #[cfg(feature = "ws")]
-use crate::lua::websocket::handle_connection;
+use crate::lua::websocket::{handle_connection, select_protocol, WSAddr, WSHandler, WSOptions};
 use crate::request::{HttpData, Request};
 #[cfg(feature = "ws")]
 use http::header::{
-    CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+    CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
 };
 #[cfg(feature = "ws")]
 use http::Method;
@@ -168,7 +169,7 @@ impl LuaUserData for LuaRequest {
         #[cfg(feature = "ws")]
         _methods.add_async_function(
             "upgrade",
-            |_, (this, func): (LuaAnyUserData, LuaFunction)| async move {
+            |lua, (this, handler, options): (LuaAnyUserData, WSHandler, Option<LuaTable>)| async move {
                 let this = this.take::<Self>()?;
                 let upgrade = HeaderValue::from_static("Upgrade");
                 let websocket = HeaderValue::from_static("websocket");
@@ -202,31 +203,69 @@ impl LuaUserData for LuaRequest {
                     ))))
                 } else {
                     let ver = this.0.req.version();
+                    let ws_options = WSOptions::from_table(options.as_ref())?;
+                    // 子协议，例如：{ protocols = { 'graphql-transport-ws', 'v12.stomp' } }
+                    let protocols: Option<Vec<String>> = match options {
+                        Some(ref options) => options.get("protocols")?,
+                        None => None,
+                    };
+                    let protocol = protocols
+                        .and_then(|p| select_protocol(headers.get(SEC_WEBSOCKET_PROTOCOL), &p));
+                    let deflate = ws_options.negotiate_deflate(headers.get(SEC_WEBSOCKET_EXTENSIONS));
+                    let mut response = Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .version(ver)
+                        .header(CONNECTION, upgrade)
+                        .header(UPGRADE, websocket)
+                        .header(SEC_WEBSOCKET_ACCEPT, derived.unwrap());
+                    if let Some(ref protocol) = protocol {
+                        response = response.header(SEC_WEBSOCKET_PROTOCOL, protocol.as_str());
+                    }
+                    let deflate = match deflate {
+                        Some((config, extension)) => {
+                            response = response.header(SEC_WEBSOCKET_EXTENSIONS, extension);
+                            Some(config)
+                        }
+                        None => None,
+                    };
+                    let response = response.body(Body::empty()).to_lua_err()?;
                     let mut req = this.0.req;
-                    let func: LuaFunction<'static> = unsafe { std::mem::transmute(func) };
+                    // 每个连接一个连接对象，state保存在user value中
+                    let state: Option<LuaTable> = match options {
+                        Some(ref options) => options.get("state")?,
+                        None => None,
+                    };
+                    let conn = lua.create_userdata(WSAddr(this.0.remote_addr))?;
+                    conn.set_user_value(match state {
+                        Some(state) => state,
+                        None => lua.create_table()?,
+                    })?;
+                    let handler: WSHandler<'static> = unsafe { std::mem::transmute(handler) };
+                    let conn: LuaAnyUserData<'static> = unsafe { std::mem::transmute(conn) };
                     tokio::task::spawn_local(async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                handle_connection(
-                                    func,
-                                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None)
-                                        .await,
+                                if let Err(e) = handle_connection(
+                                    handler,
+                                    conn,
+                                    ws_options,
+                                    protocol,
+                                    WebSocketStream::from_raw_socket(
+                                        ws_options.stream(upgraded, deflate),
+                                        Role::Server,
+                                        Some(ws_options.config()),
+                                    )
+                                    .await,
                                     this.0.remote_addr,
                                 )
                                 .await
-                                .unwrap();
+                                {
+                                    log::error!("websocket error: {e}");
+                                }
                             }
-                            Err(e) => println!("upgrade error: {e}"),
+                            Err(e) => log::error!("upgrade error: {e}"),
                         }
                     });
-                    let response = Response::builder()
-                        .status(StatusCode::SWITCHING_PROTOCOLS)
-                        .version(ver)
-                        .header(CONNECTION, upgrade)
-                        .header(UPGRADE, websocket)
-                        .header(SEC_WEBSOCKET_ACCEPT, derived.unwrap())
-                        .body(Body::empty())
-                        .to_lua_err()?;
                     Ok(HiveResponse(response))
                 }
             },
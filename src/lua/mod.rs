@@ -1,17 +1,37 @@
+pub mod cors;
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+pub mod datetime;
+pub mod debug;
+#[cfg(feature = "ws")]
+pub mod deflate;
+pub mod etag;
+#[cfg(feature = "lua_hotfix")]
+pub mod livereload;
 pub mod lua_request;
 // pub mod mysql_sqlx;
 #[cfg(feature = "mysql")]
 pub mod mysql_async;
 #[cfg(feature = "lua_hotfix")]
 pub mod notify;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod query_log;
 pub mod router;
 pub mod server;
 pub mod service;
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+pub mod sql_params;
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+pub mod sql_pool;
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+pub mod sql_value;
 
 #[cfg(feature = "ws")]
 pub mod websocket;
 #[cfg(feature = "ws")]
 pub mod ws;
+#[cfg(feature = "ws")]
+pub mod ws_client;
 
 #[cfg(feature = "lua_file_data")]
 pub mod file_data;
@@ -1,102 +1,658 @@
-use crate::error::Result;
+use crate::error::{Error as WebError, Result};
+use crate::lua::livereload::notify_browsers;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use http::{header::CONTENT_TYPE, HeaderValue, Response};
+use hyper::Body;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use mlua::prelude::*;
-use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, VecDeque};
 use std::env;
-use std::sync::Arc;
-use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{channel, Receiver};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 use crate::Args;
 
-fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
-    let (tx, rx) = channel(1);
+// watcher的回调在notify自己的线程中执行，unbounded channel发送时不需要tokio运行时
+fn async_watcher() -> notify::Result<(RecommendedWatcher, UnboundedReceiver<notify::Result<Event>>)>
+{
+    let (tx, rx) = unbounded_channel();
 
     let watcher = RecommendedWatcher::new(
         move |res| {
-            Runtime::new().unwrap().block_on(async {
-                tx.send(res).await.unwrap();
-            })
+            let _ = tx.send(res);
         },
         notify::Config::default(),
     )?;
     Ok((watcher, rx))
 }
 
+fn glob_set<I: IntoIterator<Item = String>>(globs: I) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(&glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+// 修改后需要刷新浏览器的页面模板和静态文件
+const ASSETS: &str = "**/*.{html,htm,tera,css,js,png,jpg,jpeg,gif,svg,ico}";
+
+// 防抖时间内修改过的文件
+#[derive(Default)]
+struct Changes {
+    lua: BTreeSet<PathBuf>,
+    assets: BTreeSet<PathBuf>,
+}
+
+// 过滤需要热更新的文件，路径都是相对于项目根目录
+struct Filter {
+    root: PathBuf,
+    include: GlobSet,
+    assets: GlobSet,
+    exclude: GlobSet,
+    gitignore: Gitignore,
+}
+
+impl Filter {
+    fn new(root: PathBuf, args: &Args) -> Result<Self> {
+        let include = if args.watch_include.is_empty() {
+            let ext = Path::new(&args.file)
+                .extension()
+                .and_then(|v| v.to_str())
+                .unwrap_or("lua");
+            vec![format!("**/*.{ext}")]
+        } else {
+            args.watch_include.clone()
+        };
+        let exclude = [".git/**", ".vscode/**"]
+            .iter()
+            .map(|v| v.to_string())
+            .chain(args.watch_exclude.iter().cloned());
+        let mut builder = GitignoreBuilder::new(&root);
+        let gitignore_file = root.join(".gitignore");
+        if gitignore_file.exists() {
+            if let Some(err) = builder.add(gitignore_file) {
+                log::warn!("failed to read .gitignore: {err}");
+            }
+        }
+        let gitignore = builder.build().unwrap_or_else(|err| {
+            log::warn!("failed to parse .gitignore: {err}");
+            Gitignore::empty()
+        });
+        Ok(Filter {
+            root,
+            include: glob_set(include)?,
+            assets: glob_set([ASSETS.to_string()])?,
+            exclude: glob_set(exclude)?,
+            gitignore,
+        })
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let rel = path.strip_prefix(&self.root).ok()?;
+        if self.exclude.is_match(rel)
+            || self
+                .gitignore
+                .matched_path_or_any_parents(rel, false)
+                .is_ignore()
+        {
+            None
+        } else {
+            Some(rel)
+        }
+    }
+
+    // 新建、修改、删除和重命名的文件，只修改权限等元数据时不处理
+    fn collect(&self, res: notify::Result<Event>, changes: &mut Changes) {
+        let event = match res {
+            Ok(event) => event,
+            Err(err) => {
+                log::error!("watch error: {err}");
+                return;
+            }
+        };
+        match event.kind {
+            EventKind::Modify(ModifyKind::Metadata(_)) => return,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {}
+            _ => return,
+        }
+        for path in event.paths {
+            if path.is_dir() {
+                continue;
+            }
+            if let Some(rel) = self.relative(&path) {
+                if self.include.is_match(rel) {
+                    changes.lua.insert(rel.to_path_buf());
+                } else if self.assets.is_match(rel) {
+                    changes.assets.insert(rel.to_path_buf());
+                }
+            }
+        }
+    }
+
+    // 一直等到防抖时间内没有新的事件，watcher关闭时返回None
+    async fn debounce(
+        &self,
+        rx: &mut UnboundedReceiver<notify::Result<Event>>,
+        debounce: Duration,
+    ) -> Option<Changes> {
+        let res = rx.recv().await?;
+        let mut changes = Changes::default();
+        self.collect(res, &mut changes);
+        while let Ok(Some(res)) = tokio::time::timeout(debounce, rx.recv()).await {
+            self.collect(res, &mut changes);
+        }
+        Some(changes)
+    }
+}
+
+// controllers/user.lua -> controllers.user
+fn module_name(rel: &Path) -> String {
+    rel.with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+// 热更新结果，dev模式下可以通过/__hive/status查看
+#[derive(Serialize, Clone)]
+pub struct ReloadReport {
+    time: String,
+    module: String,
+    ok: bool,
+    error: Option<String>,
+    line: Option<usize>,
+    upvalues: Vec<String>,
+    rolled_back: bool,
+}
+
+impl ReloadReport {
+    fn new(module: String, error: Option<String>) -> Self {
+        // 从错误信息中取出行号，例如：controllers/test.lua:25: ...
+        let line = error.as_deref().and_then(|err| {
+            LINE_RE
+                .captures(err)
+                .and_then(|cap| cap[1].parse::<usize>().ok())
+        });
+        ReloadReport {
+            time: httpdate::fmt_http_date(SystemTime::now()),
+            module,
+            ok: error.is_none(),
+            error,
+            line,
+            upvalues: Vec::new(),
+            rolled_back: false,
+        }
+    }
+
+    fn log(&self) {
+        if self.rolled_back {
+            log::error!(
+                "hotfix rolled back: {}, error: {}",
+                self.module,
+                self.error.as_deref().unwrap_or_default()
+            );
+        } else if self.ok {
+            log::info!(
+                "hotfix succeed: {}, upvalues: [{}]",
+                self.module,
+                self.upvalues.join(", ")
+            );
+        } else {
+            log::error!(
+                "hotfix failed: {}, error: {}",
+                self.module,
+                self.error.as_deref().unwrap_or_default()
+            );
+        }
+    }
+}
+
+pub const STATUS_PATH: &str = "/__hive/status";
+// 最多保留的热更新结果
+const MAX_REPORTS: usize = 50;
+
+static LINE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r":(\d+):").unwrap());
+static REPORTS: Lazy<Mutex<VecDeque<ReloadReport>>> = Lazy::new(Default::default);
+// 热更新成功之后还没有经过请求验证
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+fn push_report(report: ReloadReport) {
+    report.log();
+    let mut reports = REPORTS.lock().unwrap();
+    if reports.len() >= MAX_REPORTS {
+        reports.pop_front();
+    }
+    reports.push_back(report);
+}
+
+// dev模式下的热更新状态
+pub fn status() -> Response<Body> {
+    let reports = REPORTS.lock().unwrap();
+    let body = json!({
+        "pending": PENDING.load(Ordering::SeqCst),
+        "reports": reports.iter().rev().collect::<Vec<&ReloadReport>>(),
+    });
+    let mut resp = Response::new(Body::from(body.to_string()));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+// 热更新之后的第一次请求，运行出错时回滚，hive.web_error不算出错
+pub fn verify_reload(lua: &Lua, err: Option<&LuaError>) {
+    if !PENDING.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let hotfix: LuaTable = match lua.named_registry_value("hive_hotfix") {
+        Ok(hotfix) => hotfix,
+        Err(_) => return,
+    };
+    let err = err.filter(|err| WebError::from_lua_error(err).is_none());
+    let result = match err {
+        Some(err) => hotfix
+            .call_function::<_, _, Vec<String>>("rollback", ())
+            .map(|modules| {
+                for module in modules {
+                    let mut report = ReloadReport::new(module, Some(err.to_string()));
+                    report.rolled_back = true;
+                    push_report(report);
+                }
+            }),
+        None => hotfix.call_function::<_, _, ()>("commit", ()),
+    };
+    if let Err(err) = result {
+        log::error!("hotfix verify failed: {err}");
+    }
+}
+
+async fn reload_entry(lua: &Lua, args: &Args) -> Result<()> {
+    let file: Vec<u8> = tokio::fs::read(&args.file).await?;
+
+    let handler: LuaTable = lua.load(&file).eval()?;
+    lua.set_named_registry_value("http_handler", handler.get::<_, LuaFunction>("serve")?)?;
+    lua.set_named_registry_value("exception", handler.get::<_, LuaFunction>("exception")?)?;
+    Ok(())
+}
+
+// 一次防抖时间内的修改只重新加载一次
+async fn reload(
+    lua: &Lua,
+    hotfix: &LuaTable<'_>,
+    args: &Args,
+    root: &Path,
+    changes: BTreeSet<PathBuf>,
+) -> Result<()> {
+    let loaded: LuaTable = lua.globals().get::<_, LuaTable>("package")?.get("loaded")?;
+    let mut entry_changed = false;
+    for rel in changes {
+        if rel == Path::new(&args.file) {
+            entry_changed = true;
+            continue;
+        }
+        let module = module_name(&rel);
+        if !root.join(&rel).exists() {
+            // 删除或者重命名之前的文件，下次require时会报错而不是使用旧代码
+            log::info!("hotfix: {module} removed");
+            loaded.set(module, LuaValue::Nil)?;
+            continue;
+        }
+        if loaded.get::<_, LuaValue>(module.as_str())? == LuaValue::Nil {
+            // 新建的文件在第一次require时加载
+            log::info!("hotfix: {module} not loaded yet");
+            continue;
+        }
+        let report = match hotfix.call_function::<_, _, LuaTable>("reload", module.as_str()) {
+            Ok(result) => {
+                let error: Option<String> = result.get("error")?;
+                let mut report = ReloadReport::new(module, error);
+                report.upvalues = result.get("upvalues")?;
+                report
+            }
+            Err(err) => ReloadReport::new(module, Some(err.to_string())),
+        };
+        if report.ok {
+            PENDING.store(true, Ordering::SeqCst);
+        }
+        push_report(report);
+    }
+    if entry_changed {
+        let error = reload_entry(lua, args)
+            .await
+            .err()
+            .map(|err| err.to_string());
+        push_report(ReloadReport::new(args.file.clone(), error));
+    }
+    Ok(())
+}
+
 pub async fn async_watch(lua: Arc<Lua>, args: Args) -> Result<()> {
-    let hotfix: LuaFunction = lua
+    let hotfix: LuaTable = lua
         .load(include_str!("./hotfix.lua"))
         .set_name("hive[hotfix]")?
         .eval()?;
+    lua.set_named_registry_value("hive_hotfix", hotfix.clone())?;
     let (mut watcher, mut rx) = async_watcher()?;
 
-    let mut current_dir: std::path::PathBuf =
-        env::current_dir().expect("Failed to determine current directory");
-    let args_watch_dir: &str = &args.watch_dir;
-    let args_file: &str = &args.file;
-    if args_watch_dir != "." {
-        current_dir.push(args_watch_dir);
-    }
-    watcher.watch(&current_dir, RecursiveMode::Recursive)?;
-    // let mut ignore_git_dir = current_dir.clone();
-    // ignore_git_dir.push(".git");
-    // let mut ignore_vscode_dir = current_dir.clone();
-    // ignore_vscode_dir.push(".vscode");
-    // watcher.unwatch(&ignore_git_dir)?;
-    // watcher.unwatch(&ignore_vscode_dir)?;
-
-    while let Some(res) = rx.recv().await {
-        let event = res?;
-        if event.kind.is_modify() {
-            for path in event.paths {
-                let p = path.to_str();
-                if let Some(p) = p {
-                    if !p.contains(".git") && !p.contains(".vscode") {
-                        let current_dir_len = current_dir.as_os_str().len() + 1;
-                        let file = p.get(current_dir_len..);
-                        if let Some(file) = file {
-                            if file != args_file {
-                                let file = file.replace('/', ".");
-                                let data = file.rsplit_once('.');
-                                if let Some((mode, _)) = data {
-                                    let mut m;
-                                    if args_watch_dir != "." {
-                                        let watch_dir = args_watch_dir.replace('/', ".");
-                                        m = watch_dir + ".";
-                                        m += mode;
-                                    } else {
-                                        m = mode.to_string();
-                                    }
-                                    hotfix.call::<_, ()>(m)?;
-                                    let file = tokio::fs::read(args_file).await?;
-
-                                    let handler: LuaTable = lua.load(&file).eval()?;
-                                    lua.set_named_registry_value(
-                                        "http_handler",
-                                        handler.get::<_, LuaFunction>("serve")?,
-                                    )?;
-                                    lua.set_named_registry_value(
-                                        "exception",
-                                        handler.get::<_, LuaFunction>("exception")?,
-                                    )?;
-                                }
-                            } else {
-                                let file: Vec<u8> = tokio::fs::read(args_file).await?;
-
-                                let handler: LuaTable = lua.load(&file).eval()?;
-                                lua.set_named_registry_value(
-                                    "http_handler",
-                                    handler.get::<_, LuaFunction>("serve")?,
-                                )?;
-                                lua.set_named_registry_value(
-                                    "exception",
-                                    handler.get::<_, LuaFunction>("exception")?,
-                                )?;
-                            }
-                        }
-                    }
-                }
+    let root: PathBuf = env::current_dir().expect("Failed to determine current directory");
+    watcher.watch(&root.join(&args.watch_dir), RecursiveMode::Recursive)?;
+    let filter = Filter::new(root.clone(), &args)?;
+    let debounce = Duration::from_millis(args.watch_debounce);
+
+    while let Some(changes) = filter.debounce(&mut rx, debounce).await {
+        if changes.lua.is_empty() && changes.assets.is_empty() {
+            continue;
+        }
+        // 只修改了css时替换样式表，否则刷新页面
+        let css_only = changes.lua.is_empty()
+            && changes
+                .assets
+                .iter()
+                .all(|p| p.extension().map(|ext| ext == "css").unwrap_or(false));
+        let css: Vec<String> = if css_only {
+            changes
+                .assets
+                .iter()
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !changes.lua.is_empty() {
+            if let Err(err) = reload(&lua, &hotfix, &args, &root, changes.lua).await {
+                log::error!("hotfix failed: {err}");
             }
         }
+        notify_browsers(css);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind};
+
+    fn filter(name: &str, args: &[&str], gitignore: &str) -> Filter {
+        let root = env::temp_dir().join(format!("hive_notify_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".gitignore"), gitignore).unwrap();
+        let args = Args::parse_from(["hive"].iter().chain(args));
+        Filter::new(root, &args).unwrap()
+    }
+
+    fn event(kind: EventKind, filter: &Filter, paths: &[&str]) -> notify::Result<Event> {
+        let mut event = Event::new(kind);
+        for path in paths {
+            event = event.add_path(filter.root.join(path));
+        }
+        Ok(event)
+    }
+
+    fn modify(filter: &Filter, paths: &[&str]) -> notify::Result<Event> {
+        event(
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            filter,
+            paths,
+        )
+    }
+
+    fn paths(set: &BTreeSet<PathBuf>) -> Vec<&str> {
+        set.iter().map(|p| p.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn default_filter() {
+        let filter = filter("default", &[], "target/\n*.bak.lua\n");
+        let mut changes = Changes::default();
+        filter.collect(
+            modify(
+                &filter,
+                &[
+                    "index.lua",
+                    "controllers/user.lua",
+                    "views/index.html",
+                    "static/app.css",
+                    "README.md",
+                    ".git/hooks/a.lua",
+                    "target/debug/a.lua",
+                    "old.bak.lua",
+                ],
+            ),
+            &mut changes,
+        );
+        assert_eq!(paths(&changes.lua), ["controllers/user.lua", "index.lua"]);
+        assert_eq!(
+            paths(&changes.assets),
+            ["static/app.css", "views/index.html"]
+        );
+        // 项目目录以外的文件不处理
+        assert!(filter.relative(Path::new("/other/a.lua")).is_none());
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = filter(
+            "include",
+            &[
+                "--watch-include",
+                "controllers/**/*.lua,lib/*.lua",
+                "--watch-exclude",
+                "controllers/tests/**",
+            ],
+            "",
+        );
+        let mut changes = Changes::default();
+        filter.collect(
+            modify(
+                &filter,
+                &[
+                    "controllers/user.lua",
+                    "controllers/api/v1.lua",
+                    "controllers/tests/user.lua",
+                    "lib/json.lua",
+                    "views/json.lua",
+                    "index.lua",
+                ],
+            ),
+            &mut changes,
+        );
+        assert_eq!(
+            paths(&changes.lua),
+            [
+                "controllers/api/v1.lua",
+                "controllers/user.lua",
+                "lib/json.lua"
+            ]
+        );
+        assert!(changes.assets.is_empty());
+    }
+
+    #[test]
+    fn skip_metadata_and_access() {
+        let filter = filter("kind", &[], "");
+        let mut changes = Changes::default();
+        filter.collect(
+            event(
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+                &filter,
+                &["a.lua"],
+            ),
+            &mut changes,
+        );
+        filter.collect(
+            event(EventKind::Access(AccessKind::Read), &filter, &["b.lua"]),
+            &mut changes,
+        );
+        filter.collect(
+            event(EventKind::Create(CreateKind::File), &filter, &["c.lua"]),
+            &mut changes,
+        );
+        filter.collect(Err(notify::Error::generic("watch failed")), &mut changes);
+        assert_eq!(paths(&changes.lua), ["c.lua"]);
+    }
+
+    #[tokio::test]
+    async fn debounce_groups_events() {
+        let filter = filter("debounce", &[], "");
+        let debounce = Duration::from_millis(100);
+        let (tx, mut rx) = unbounded_channel();
+        tx.send(modify(&filter, &["a.lua"])).unwrap();
+        tx.send(modify(&filter, &["b.lua", "a.lua"])).unwrap();
+        tx.send(modify(&filter, &["style.css"])).unwrap();
+        let sender = tx.clone();
+        let later = modify(&filter, &["c.lua"]);
+        tokio::spawn(async move {
+            // 防抖时间内的事件合并到同一组
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            sender.send(later).unwrap();
+        });
+
+        let changes = filter.debounce(&mut rx, debounce).await.unwrap();
+        assert_eq!(paths(&changes.lua), ["a.lua", "b.lua", "c.lua"]);
+        assert_eq!(paths(&changes.assets), ["style.css"]);
+
+        tx.send(modify(&filter, &["d.lua"])).unwrap();
+        let changes = filter.debounce(&mut rx, debounce).await.unwrap();
+        assert_eq!(paths(&changes.lua), ["d.lua"]);
+
+        drop(tx);
+        assert!(filter.debounce(&mut rx, debounce).await.is_none());
+    }
+
+    #[test]
+    fn report_line() {
+        let report = ReloadReport::new(
+            "controllers.test".to_string(),
+            Some("controllers/test.lua:25: attempt to call a nil value".to_string()),
+        );
+        assert!(!report.ok);
+        assert_eq!(report.line, Some(25));
+        let report = ReloadReport::new("controllers.test".to_string(), None);
+        assert!(report.ok);
+        assert_eq!(report.line, None);
+        assert_eq!(
+            module_name(Path::new("controllers/api/user.lua")),
+            "controllers.api.user"
+        );
+    }
+
+    // 模块的源码放在全局变量source中，修改source之后reload
+    fn load_hotfix(lua: &Lua) -> LuaTable<'_> {
+        let hotfix: LuaTable = lua.load(include_str!("./hotfix.lua")).eval().unwrap();
+        lua.globals().set("hotfix", hotfix.clone()).unwrap();
+        lua.load(
+            r#"
+            source = [[
+                local count = 0
+                return { inc = function() count = count + 1 return count end, version = 1 }
+            ]]
+            package.preload.counter = function() return assert(load(source))() end
+            counter = require('counter')
+            assert(counter.inc() == 1)
+            "#,
+        )
+        .exec()
+        .unwrap();
+        hotfix
+    }
+
+    #[test]
+    fn hotfix_reload_and_rollback() {
+        // hotfix.lua需要debug库
+        let lua = unsafe { Lua::unsafe_new() };
+        load_hotfix(&lua);
+        lua.load(
+            r#"
+            source = [[
+                local count = 0
+                return { inc = function() count = count + 10 return count end, version = 2, extra = true }
+            ]]
+            local report = hotfix.reload('counter')
+            assert(report.ok and report.error == nil, report.error)
+            assert(report.upvalues[1] == 'count' and #report.upvalues == 1)
+            -- 原地更新旧模块，upvalue保留旧值
+            assert(package.loaded.counter == counter)
+            assert(counter.inc() == 11 and counter.version == 1 and counter.extra)
+
+            local modules = hotfix.rollback()
+            assert(#modules == 1 and modules[1] == 'counter')
+            assert(package.loaded.counter == counter)
+            -- 回滚后恢复旧函数，旧函数的upvalue没有变化
+            assert(counter.inc() == 2 and counter.extra == nil)
+            assert(#hotfix.rollback() == 0)
+
+            -- commit之后不能再回滚
+            hotfix.reload('counter')
+            hotfix.commit()
+            assert(#hotfix.rollback() == 0)
+            assert(counter.inc() == 12)
+            "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn hotfix_reload_error() {
+        let lua = unsafe { Lua::unsafe_new() };
+        load_hotfix(&lua);
+        lua.load(
+            r#"
+            source = 'return {'
+            local report = hotfix.reload('counter')
+            assert(not report.ok and report.error:find(':1:'))
+            -- 加载失败时保留旧模块
+            assert(package.loaded.counter == counter and counter.inc() == 2)
+            assert(#hotfix.rollback() == 0)
+
+            report = hotfix.reload('not_loaded')
+            assert(not report.ok and report.error == 'module not loaded')
+            "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn verify_reload_rolls_back() {
+        let lua = unsafe { Lua::unsafe_new() };
+        let hotfix = load_hotfix(&lua);
+        lua.set_named_registry_value("hive_hotfix", hotfix).unwrap();
+        let reload = |step: i64| {
+            let code = format!(
+                "source = 'local count = 0 return {{ inc = function() count = count + {step} return count end }}'
+                hotfix.reload('counter')"
+            );
+            lua.load(&code).exec().unwrap();
+        };
+
+        // hive.web_error不算出错，修改被保留
+        reload(10);
+        PENDING.store(true, Ordering::SeqCst);
+        let err = LuaError::ExternalError(Arc::new(WebError::new(4001, "bad request")));
+        verify_reload(&lua, Some(&err));
+        assert_eq!(lua.load("counter.inc()").eval::<i64>().unwrap(), 11);
+
+        reload(100);
+        PENDING.store(true, Ordering::SeqCst);
+        let err = LuaError::RuntimeError("controllers/test.lua:3: boom".to_string());
+        verify_reload(&lua, Some(&err));
+        assert!(!PENDING.load(Ordering::SeqCst));
+        assert_eq!(lua.load("counter.inc()").eval::<i64>().unwrap(), 21);
+        let reports = REPORTS.lock().unwrap();
+        let report = reports.back().unwrap();
+        assert!(report.rolled_back && report.module == "counter");
+        assert_eq!(report.line, Some(3));
+    }
+}
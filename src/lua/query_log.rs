@@ -0,0 +1,136 @@
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+use std::time::{Duration, Instant};
+
+// 一次数据库调用的记录
+#[derive(Clone)]
+pub struct QueryRecord {
+    pub sql: String,
+    pub params: usize,
+    // 查询返回的行数或者写入影响的行数，出错时为None
+    pub rows: Option<u64>,
+    pub millis: f64,
+    pub error: Option<String>,
+}
+
+impl QueryRecord {
+    fn to_table<'lua>(&self, lua: &'lua Lua) -> LuaResult<LuaTable<'lua>> {
+        let table = lua.create_table()?;
+        table.set("sql", self.sql.as_str())?;
+        table.set("params", self.params)?;
+        table.set("rows", self.rows)?;
+        table.set("time", self.millis)?;
+        table.set("error", self.error.as_deref())?;
+        Ok(table)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct QueryLog(Rc<RefCell<Vec<QueryRecord>>>);
+
+tokio::task_local! {
+    // dev模式下每个请求单独记录
+    static QUERY_LOG: QueryLog;
+}
+
+// 在future中执行的数据库调用都会记录下来
+pub async fn scope<F: Future>(fut: F) -> F::Output {
+    QUERY_LOG.scope(QueryLog::default(), fut).await
+}
+
+// 当前请求执行过的数据库调用，不在scope中时为空
+pub fn records() -> Vec<QueryRecord> {
+    QUERY_LOG
+        .try_with(|log| log.0.borrow().clone())
+        .unwrap_or_default()
+}
+
+pub fn total_millis(records: &[QueryRecord]) -> f64 {
+    records.iter().fold(0.0, |total, r| total + r.millis)
+}
+
+// hive.query_log()，返回{ queries = {...}, total_time = 毫秒 }
+pub fn create_query_log(lua: &Lua) -> LuaResult<LuaFunction<'_>> {
+    lua.create_function(|lua, ()| {
+        let records = records();
+        let queries = lua.create_sequence_from(
+            records
+                .iter()
+                .map(|r| r.to_table(lua))
+                .collect::<LuaResult<Vec<LuaTable>>>()?,
+        )?;
+        let table = lua.create_table()?;
+        table.set("queries", queries)?;
+        table.set("total_time", total_millis(&records))?;
+        Ok(table)
+    })
+}
+
+// 开始执行sql时创建，结束时写日志，超过slow_query的用warn级别
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+pub struct QueryTimer {
+    sql: String,
+    params: usize,
+    start: Instant,
+    slow_query: Option<Duration>,
+    // 在spawn_local的任务中结束时也能记录到发起请求的列表
+    log: Option<QueryLog>,
+}
+
+#[cfg(any(feature = "mysql", feature = "postgres"))]
+impl QueryTimer {
+    pub fn start(sql: &str, params: usize, slow_query: Option<Duration>) -> Self {
+        QueryTimer {
+            sql: sql.to_string(),
+            params,
+            start: Instant::now(),
+            slow_query,
+            log: QUERY_LOG.try_with(|log| log.clone()).ok(),
+        }
+    }
+
+    pub fn finish<E: ToString>(self, result: Result<u64, &E>) {
+        let elapsed = self.start.elapsed();
+        let (rows, error) = match result {
+            Ok(rows) => (Some(rows), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        if let Some(ref err) = error {
+            log::error!(
+                "Query -- sql: {}, params: {}, time: {:?}, error: {}",
+                self.sql,
+                self.params,
+                elapsed,
+                err
+            );
+        } else if self.slow_query.map(|slow| elapsed >= slow).unwrap_or(false) {
+            log::warn!(
+                "Slow query -- sql: {}, params: {}, rows: {}, time: {:?}",
+                self.sql,
+                self.params,
+                rows.unwrap_or(0),
+                elapsed
+            );
+        } else {
+            log::info!(
+                "Query -- sql: {}, params: {}, rows: {}, time: {:?}",
+                self.sql,
+                self.params,
+                rows.unwrap_or(0),
+                elapsed
+            );
+        }
+        if let Some(log) = self.log {
+            log.0.borrow_mut().push(QueryRecord {
+                sql: self.sql,
+                params: self.params,
+                rows,
+                millis: elapsed.as_secs_f64() * 1000.0,
+                error,
+            });
+        }
+    }
+}
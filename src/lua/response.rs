@@ -1,24 +1,116 @@
-use http::{response::Builder, Response, Version};
-use hyper::Body;
+use futures_util::{stream, StreamExt};
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    response::Builder,
+    HeaderMap, Response, StatusCode, Version,
+};
+use hyper::{body::Bytes, Body};
 use mlua::prelude::*;
 
+use super::etag::EtagSetting;
+
 pub struct HiveResponseBuilder(Builder);
 
+// lua值转换为响应体，字符串原样输出，其他类型序列化为json
+fn lua_value_to_bytes(body: LuaValue) -> LuaResult<Vec<u8>> {
+    match body {
+        LuaValue::String(v) => Ok(v.as_bytes().to_vec()),
+        _ => serde_json::to_vec(&body).to_lua_err(),
+    }
+}
+
+fn lua_value_to_body(body: LuaValue) -> LuaResult<Body> {
+    Ok(Body::from(lua_value_to_bytes(body)?))
+}
+
+// 同名header有多个值时返回数组，否则返回字符串
+fn header_values_to_lua<'lua>(
+    lua: &'lua Lua,
+    headers: &HeaderMap,
+    name: &HeaderName,
+) -> LuaResult<LuaValue<'lua>> {
+    let values: Vec<&HeaderValue> = headers.get_all(name).iter().collect();
+    match values.len() {
+        0 => Ok(LuaValue::Nil),
+        1 => {
+            let val: &str = values[0].to_str().to_lua_err()?;
+            Ok(LuaValue::String(lua.create_string(val)?))
+        }
+        _ => {
+            let table: LuaTable = lua.create_table()?;
+            for (i, val) in values.into_iter().enumerate() {
+                table.set(i + 1, val.to_str().to_lua_err()?)?;
+            }
+            Ok(LuaValue::Table(table))
+        }
+    }
+}
+
+// 根据状态码、Content-Type和响应体直接生成响应，同时设置Content-Length
+fn quick_response(status: u16, content_type: &str, body: Vec<u8>) -> LuaResult<HiveResponse<Body>> {
+    let resp = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .to_lua_err()?;
+    Ok(HiveResponse(resp))
+}
+
 impl LuaUserData for HiveResponseBuilder {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
         _methods.add_function("new", |_, ()| Ok(HiveResponseBuilder(Response::builder())));
+        // 空数组需要使用hive.empty_array()，否则空table会被序列化为{}
+        _methods.add_function("json", |_, (value, status): (LuaValue, Option<u16>)| {
+            let body = serde_json::to_vec(&value).to_lua_err()?;
+            quick_response(status.unwrap_or(200), "application/json", body)
+        });
+        _methods.add_function("html", |_, (body, status): (LuaString, Option<u16>)| {
+            quick_response(
+                status.unwrap_or(200),
+                "text/html; charset=utf-8",
+                body.as_bytes().to_vec(),
+            )
+        });
+        _methods.add_function("text", |_, (body, status): (LuaString, Option<u16>)| {
+            quick_response(
+                status.unwrap_or(200),
+                "text/plain; charset=utf-8",
+                body.as_bytes().to_vec(),
+            )
+        });
+        _methods.add_function(
+            "bytes",
+            |_, (body, content_type, status): (LuaString, Option<String>, Option<u16>)| {
+                let content_type =
+                    content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+                quick_response(
+                    status.unwrap_or(200),
+                    &content_type,
+                    body.as_bytes().to_vec(),
+                )
+            },
+        );
+        // 默认302临时重定向
+        _methods.add_function("redirect", |_, (url, status): (String, Option<u16>)| {
+            let resp = Response::builder()
+                .status(status.unwrap_or(302))
+                .header(LOCATION, url)
+                .header(CONTENT_LENGTH, 0)
+                .body(Body::empty())
+                .to_lua_err()?;
+            Ok(HiveResponse(resp))
+        });
+        _methods.add_function("no_content", |_, ()| {
+            let resp = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .to_lua_err()?;
+            Ok(HiveResponse(resp))
+        });
         _methods.add_function("body", |_, (this, body): (LuaAnyUserData, LuaValue)| {
             let this = this.take::<Self>()?;
-            let resp = match body {
-                LuaValue::String(v) => {
-                    let data = v.as_bytes().to_vec();
-                    this.0.body(Body::from(data)).to_lua_err()?
-                }
-                _ => {
-                    let body = serde_json::to_vec(&body).to_lua_err()?;
-                    this.0.body(Body::from(body)).to_lua_err()?
-                }
-            };
+            let resp = this.0.body(lua_value_to_body(body)?).to_lua_err()?;
             Ok(HiveResponse(resp))
         });
         _methods.add_function("status", |_, (this, status): (LuaAnyUserData, u16)| {
@@ -40,14 +132,22 @@ impl LuaUserData for HiveResponseBuilder {
             };
             Ok(HiveResponseBuilder(this.0.version(ver)))
         });
+        // 值为数组时，同名header会被添加多次，例如：{ ['Set-Cookie'] = { 'a=1', 'b=2' } }
         _methods.add_function(
             "headers",
-            |_, (this, headers): (LuaAnyUserData, LuaTable)| {
+            |lua, (this, headers): (LuaAnyUserData, LuaTable)| {
                 let this = this.take::<Self>()?;
                 let mut resp = this.0;
-                for pair in headers.pairs::<String, String>() {
+                for pair in headers.pairs::<String, LuaValue>() {
                     let (h, v) = pair.to_lua_err()?;
-                    resp = resp.header(h, v);
+                    if let LuaValue::Table(values) = v {
+                        for val in values.sequence_values::<String>() {
+                            resp = resp.header(h.as_str(), val?);
+                        }
+                    } else {
+                        let val = String::from_lua(v, lua)?;
+                        resp = resp.header(h, val);
+                    }
                 }
                 Ok(HiveResponseBuilder(resp))
             },
@@ -57,4 +157,140 @@ impl LuaUserData for HiveResponseBuilder {
 
 pub struct HiveResponse<T>(pub Response<T>);
 
-impl<T> LuaUserData for HiveResponse<T> {}
+impl LuaUserData for HiveResponse<Body> {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_method("status", |_, this, ()| Ok(this.0.status().as_u16()));
+        _methods.add_method_mut("set_status", |_, this, status: u16| {
+            *this.0.status_mut() = StatusCode::from_u16(status).to_lua_err()?;
+            Ok(())
+        });
+        _methods.add_method("headers", |lua, this, ()| {
+            let headers: LuaTable = lua.create_table()?;
+            let headers_raw: &HeaderMap = this.0.headers();
+            for key in headers_raw.keys() {
+                headers.set(key.as_str(), header_values_to_lua(lua, headers_raw, key)?)?;
+            }
+            Ok(headers)
+        });
+        _methods.add_method("header", |lua, this, name: String| {
+            let name = HeaderName::from_bytes(name.as_bytes()).to_lua_err()?;
+            header_values_to_lua(lua, this.0.headers(), &name)
+        });
+        // 覆盖同名header的所有值
+        _methods.add_method_mut("set_header", |_, this, (name, value): (String, String)| {
+            let name = HeaderName::from_bytes(name.as_bytes()).to_lua_err()?;
+            let value = HeaderValue::from_str(&value).to_lua_err()?;
+            this.0.headers_mut().insert(name, value);
+            Ok(())
+        });
+        // 保留已有的值，追加一个同名header
+        _methods.add_method_mut(
+            "append_header",
+            |_, this, (name, value): (String, String)| {
+                let name = HeaderName::from_bytes(name.as_bytes()).to_lua_err()?;
+                let value = HeaderValue::from_str(&value).to_lua_err()?;
+                this.0.headers_mut().append(name, value);
+                Ok(())
+            },
+        );
+        _methods.add_method_mut("remove_header", |_, this, name: String| {
+            let name = HeaderName::from_bytes(name.as_bytes()).to_lua_err()?;
+            this.0.headers_mut().remove(name);
+            Ok(())
+        });
+        // 读取响应体，读取后响应体会被重新写回，不影响输出
+        _methods.add_async_function("body", |lua, this: LuaAnyUserData| async move {
+            let mut body: Body = std::mem::take(this.borrow_mut::<Self>()?.0.body_mut());
+            let mut data: Vec<u8> = Vec::new();
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => data.extend_from_slice(&chunk),
+                    // 读取失败时把已经读到的数据和剩下的响应体放回去
+                    Err(err) => {
+                        let read = stream::once(async { Ok(Bytes::from(data)) });
+                        *this.borrow_mut::<Self>()?.0.body_mut() =
+                            Body::wrap_stream(read.chain(body));
+                        return Err(err).to_lua_err();
+                    }
+                }
+            }
+            let body = lua.create_string(&data)?;
+            *this.borrow_mut::<Self>()?.0.body_mut() = Body::from(data);
+            Ok(body)
+        });
+        // 替换响应体，同时更新Content-Length，204和304不能有Content-Length
+        _methods.add_method_mut("set_body", |_, this, body: LuaValue| {
+            let body = lua_value_to_bytes(body)?;
+            let status = this.0.status();
+            if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+                this.0.headers_mut().remove(CONTENT_LENGTH);
+            } else {
+                this.0
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, body.len().into());
+            }
+            *this.0.body_mut() = Body::from(body);
+            Ok(())
+        });
+        // 单独开启或关闭此响应的ETag，覆盖路由和服务器设置
+        _methods.add_method_mut("etag", |_, this, enable: Option<bool>| {
+            this.0
+                .extensions_mut()
+                .insert(EtagSetting(enable.unwrap_or(true)));
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call<'lua>(lua: &'lua Lua, code: &str) -> LuaFunction<'lua> {
+        lua.load(code).eval().unwrap()
+    }
+
+    #[tokio::test]
+    async fn body_can_be_read_twice() {
+        let lua = Lua::new();
+        let resp = HiveResponse(Response::new(Body::from("hello")));
+        let body = call(&lua, "function(resp) return resp:body() .. resp:body() end");
+        let text: String = body.call_async(resp).await.unwrap();
+        assert_eq!(text, "hellohello");
+    }
+
+    #[tokio::test]
+    async fn body_is_kept_when_reading_fails() {
+        let lua = Lua::new();
+        let (mut sender, body) = Body::channel();
+        sender.send_data(Bytes::from("partial")).await.unwrap();
+        sender.abort();
+        let resp = lua
+            .create_userdata(HiveResponse(Response::new(body)))
+            .unwrap();
+        let body = call(&lua, "function(resp) return resp:body() end");
+        assert!(body.call_async::<_, String>(resp.clone()).await.is_err());
+        let mut resp = resp.take::<HiveResponse<Body>>().unwrap();
+        let first = resp.0.body_mut().next().await.unwrap().unwrap();
+        assert_eq!(first, "partial");
+    }
+
+    #[test]
+    fn set_body_updates_content_length() {
+        let lua = Lua::new();
+        let set_body = call(
+            &lua,
+            "function(resp, body) resp:set_body(body) return resp end",
+        );
+        let resp = HiveResponse(Response::new(Body::empty()));
+        let resp: LuaAnyUserData = set_body.call((resp, "abc")).unwrap();
+        let resp = resp.take::<HiveResponse<Body>>().unwrap();
+        assert_eq!(resp.0.headers()[CONTENT_LENGTH], "3");
+        let mut not_modified = Response::new(Body::empty());
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        not_modified.headers_mut().insert(CONTENT_LENGTH, 10.into());
+        let resp: LuaAnyUserData = set_body.call((HiveResponse(not_modified), "")).unwrap();
+        let resp = resp.take::<HiveResponse<Body>>().unwrap();
+        assert!(resp.0.headers().get(CONTENT_LENGTH).is_none());
+    }
+}
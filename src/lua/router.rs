@@ -1,14 +1,85 @@
-use crate::error::Result;
 use mlua::prelude::*;
+#[cfg(feature = "lua_hotfix")]
+use std::cell::RefCell;
 use std::collections::HashMap;
+#[cfg(feature = "lua_hotfix")]
+use std::future::Future;
 
-// Router<(function, middleware)>
-type Router =
-    HashMap<String, matchit::Router<(LuaFunction<'static>, Option<LuaFunction<'static>>)>>;
+// 路由选项，例如：router:match('GET', '/list', func, nil, { etag = true })
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RouteOptions {
+    pub etag: Option<bool>,
+}
+
+impl<'lua> FromLua<'lua> for RouteOptions {
+    fn from_lua(value: LuaValue<'lua>, _: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(RouteOptions::default()),
+            LuaValue::Table(t) => Ok(RouteOptions {
+                etag: t.get("etag")?,
+            }),
+            _ => Err(LuaError::FromLuaConversionError {
+                from: value.type_name(),
+                to: "RouteOptions",
+                message: Some("route options must be a table".to_string()),
+            }),
+        }
+    }
+}
+
+// router:execute匹配到的路由，用于路由选项和dev模式下的调试页面
+#[cfg(feature = "lua_hotfix")]
+#[derive(Default)]
+pub struct RouteMatch {
+    pub route: Option<String>,
+    pub params: Vec<(String, String)>,
+    pub options: RouteOptions,
+}
+
+#[cfg(feature = "lua_hotfix")]
+tokio::task_local! {
+    // lua_hotfix下由lua调用router:execute，匹配到的路由记录在这里
+    static MATCHED: RefCell<RouteMatch>;
+}
+
+// 执行future，同时返回其中router:execute匹配到的路由
+#[cfg(feature = "lua_hotfix")]
+pub async fn scope<F: Future>(fut: F) -> (F::Output, RouteMatch) {
+    MATCHED
+        .scope(RefCell::new(RouteMatch::default()), async move {
+            let output = fut.await;
+            (output, MATCHED.with(|matched| matched.take()))
+        })
+        .await
+}
+
+// Router<(function, middleware, options, path)>
+type Router = HashMap<
+    String,
+    matchit::Router<(
+        LuaFunction<'static>,
+        Option<LuaFunction<'static>>,
+        RouteOptions,
+        String,
+    )>,
+>;
 
 pub struct HiveRouter(Router);
 
 impl HiveRouter {
+    // 返回匹配到的路由及路由参数，用于dev模式下的调试页面
+    #[allow(dead_code)]
+    pub fn route_info(&self, method: &str, path: &str) -> Option<(String, Vec<(String, String)>)> {
+        let router = self.0.get(&method.to_uppercase())?;
+        let matched = router.at(path).ok()?;
+        let params: Vec<(String, String)> = matched
+            .params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Some((matched.value.3.clone(), params))
+    }
+
     #[allow(dead_code)]
     pub async fn execute<'a>(
         &'a self,
@@ -17,12 +88,12 @@ impl HiveRouter {
         request: LuaAnyUserData<'a>,
         _exception: LuaFunction<'a>,
         _next: Option<LuaFunction<'a>>,
-    ) -> Result<LuaValue> {
+    ) -> LuaResult<(LuaValue<'a>, RouteOptions)> {
         let value = self.0.get(&method.to_uppercase());
         if let Some(router) = value {
             let matched = router.at(&path);
             if let Ok(matched) = matched {
-                let (func, middleware) = matched.value;
+                let (func, middleware, options, _) = matched.value;
                 let router_params = matched.params;
                 let mut params: HashMap<&str, &str> = HashMap::new();
                 for (key, val) in router_params.iter() {
@@ -32,9 +103,9 @@ impl HiveRouter {
                     let data = _next
                         .call_async((true, func.clone(), middleware.clone(), request, params))
                         .await?;
-                    Ok(data)
+                    Ok((data, *options))
                 } else {
-                    Ok(LuaValue::Nil)
+                    Ok((LuaValue::Nil, *options))
                 }
             } else if let Some(_next) = _next {
                 let data = _next
@@ -46,9 +117,9 @@ impl HiveRouter {
                         LuaValue::Nil,
                     ))
                     .await?;
-                Ok(data)
+                Ok((data, RouteOptions::default()))
             } else {
-                Ok(LuaValue::Nil)
+                Ok((LuaValue::Nil, RouteOptions::default()))
             }
         } else if let Some(_next) = _next {
             let data = _next
@@ -60,9 +131,9 @@ impl HiveRouter {
                     LuaValue::Nil,
                 ))
                 .await?;
-            Ok(data)
+            Ok((data, RouteOptions::default()))
         } else {
-            Ok(LuaValue::Nil)
+            Ok((LuaValue::Nil, RouteOptions::default()))
         }
     }
 }
@@ -77,11 +148,12 @@ impl LuaUserData for HiveRouter {
             "match",
             |_,
              this,
-             (method, path, func, middleware): (
+             (method, path, func, middleware, options): (
                 String,
                 String,
                 LuaFunction,
                 Option<LuaFunction>,
+                RouteOptions,
             )| {
                 let func: LuaFunction<'static> = unsafe { std::mem::transmute(func) };
                 if let Some(middleware) = middleware {
@@ -90,13 +162,13 @@ impl LuaUserData for HiveRouter {
                     this.0
                         .entry(method.to_uppercase())
                         .or_default()
-                        .insert(path, (func, Some(middleware)))
+                        .insert(path.clone(), (func, Some(middleware), options, path))
                         .to_lua_err()?;
                 } else {
                     this.0
                         .entry(method.to_uppercase())
                         .or_default()
-                        .insert(path, (func, None))
+                        .insert(path.clone(), (func, None, options, path))
                         .to_lua_err()?;
                 }
                 Ok(())
@@ -108,16 +180,28 @@ impl LuaUserData for HiveRouter {
             if let Some(router) = value {
                 let matched = router.at(&path);
                 if let Ok(matched) = matched {
-                    let (func, middleware) = matched.value;
+                    let (func, middleware, options, _route) = matched.value;
                     let params = lua.create_table()?;
                     let router_params = matched.params;
                     for (key, val) in router_params.iter() {
                         params.set(key, val)?;
                     }
+                    #[cfg(feature = "lua_hotfix")]
+                    let _ = MATCHED.try_with(|matched| {
+                        *matched.borrow_mut() = RouteMatch {
+                            route: Some(_route.clone()),
+                            params: router_params
+                                .iter()
+                                .map(|(k, v)| (k.to_string(), v.to_string()))
+                                .collect(),
+                            options: *options,
+                        }
+                    });
                     table.set("is_exist", true)?;
                     table.set("func", func.clone())?;
                     table.set("middleware", middleware.clone())?;
                     table.set("router_params", params)?;
+                    table.set("etag", options.etag)?;
                     Ok(table)
                 } else {
                     table.set("is_exist", false)?;
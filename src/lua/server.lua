@@ -4,7 +4,10 @@ local server = {
   _port = 3000,
   _exception = nil,
   _serve = nil,
-  _router = nil
+  _router = nil,
+  _etag = false,
+  _cors = nil,
+  _livereload = false
 }
 
 ---绑定ip和端口
@@ -48,6 +51,30 @@ function server:router(router)
   return self
 end
 
+---根据响应体自动生成ETag，并处理If-None-Match和If-Modified-Since
+---@param enable boolean
+---@return table
+function server:etag(enable)
+  self._etag = enable ~= false
+  return self
+end
+
+---跨域设置，预检请求会在路由之前直接返回
+---@param options table { origins = '*' | table | function, origin_regex = string, methods = table, headers = table, credentials = boolean, max_age = number, expose_headers = table }
+---@return table
+function server:cors(options)
+  self._cors = options or {}
+  return self
+end
+
+---dev模式下开启lua_hotfix时，在html响应中插入浏览器自动刷新脚本
+---@param enable boolean
+---@return table
+function server:livereload(enable)
+  self._livereload = enable ~= false
+  return self
+end
+
 function server:run()
   return {
     ['addr'] = self._addr,
@@ -55,7 +82,10 @@ function server:run()
     ['exception'] = self._exception,
     ['serve'] = self._serve,
     ['is_ipv4'] = self._is_ipv4,
-    ['router'] = self._router
+    ['router'] = self._router,
+    ['etag'] = self._etag,
+    ['cors'] = self._cors,
+    ['livereload'] = self._livereload
   }
 end
 
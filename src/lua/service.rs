@@ -1,16 +1,22 @@
 use super::lua_request::LuaRequest;
 use crate::error::Error as WebError;
+use crate::lua::cors::Cors;
+use crate::lua::debug::{debug_page, DebugInfo};
+use crate::lua::etag::Conditional;
+use crate::lua::query_log;
 use crate::lua::response::HiveResponse;
-use crate::lua::router::HiveRouter;
+use crate::lua::router::{HiveRouter, RouteOptions};
+#[cfg(feature = "lua_hotfix")]
+use crate::lua::{livereload, notify, router};
 use futures_util::Future;
 
 #[cfg(feature = "h2")]
 use crate::LocalExec;
 #[cfg(feature = "h2")]
 use http::header::UPGRADE;
-#[cfg(feature = "h2")]
 use http::StatusCode;
 // use http::Version;
+use http::{header::ORIGIN, HeaderValue};
 use hyper::{server::conn::AddrStream, service::Service, Body, Request, Response};
 use mlua::prelude::*;
 use std::net::SocketAddr;
@@ -19,6 +25,9 @@ use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
+type HandlerFuture =
+    Pin<Box<dyn Future<Output = Result<(Response<Body>, RouteOptions), WebError>>>>;
+
 // pub struct Svc(Arc<Lua>, SocketAddr);
 pub struct Svc {
     lua: Arc<Lua>,
@@ -26,6 +35,11 @@ pub struct Svc {
     handler: Option<Arc<LuaRegistryKey>>,
     exception: Arc<LuaRegistryKey>,
     router: Option<Arc<HiveRouter>>,
+    etag: bool,
+    cors: Option<Arc<Cors>>,
+    dev: bool,
+    #[cfg(feature = "lua_hotfix")]
+    livereload: bool,
 }
 
 impl Service<Request<Body>> for Svc {
@@ -39,12 +53,41 @@ impl Service<Request<Body>> for Svc {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let lua: Arc<Lua> = self.lua.clone();
+        let cors: Option<Arc<Cors>> = self.cors.clone();
+        // 浏览器自动刷新的脚本和websocket，不经过路由
+        #[cfg(feature = "lua_hotfix")]
+        if self.dev && livereload::is_livereload(req.uri().path()) {
+            return Box::pin(async move { Ok(livereload::handle(req)) });
+        }
+        #[cfg(feature = "lua_hotfix")]
+        if self.dev && req.uri().path() == notify::STATUS_PATH {
+            return Box::pin(async move { Ok(notify::status()) });
+        }
+        // HEAD等请求的响应体可能为空，只在GET请求的页面中插入脚本
+        #[cfg(feature = "lua_hotfix")]
+        let inject_livereload = self.livereload.then(|| req.method().clone());
+        // 预检请求直接返回，不经过路由
+        if let Some(cors) = cors.clone() {
+            if Cors::is_preflight(req.method(), req.headers()) {
+                let headers = req.headers().clone();
+                return Box::pin(async move { Ok(cors.preflight(&lua, &headers).await) });
+            }
+        }
+        let origin: Option<HeaderValue> = req.headers().get(ORIGIN).cloned();
+        let cors_lua: Arc<Lua> = lua.clone();
         let method: String = req.method().as_str().to_string();
         let path: String = req.uri().path().to_string();
+        let conditional: Conditional = Conditional::new(req.method(), req.headers());
+        let debug_info: Option<DebugInfo> = if self.dev {
+            Some(DebugInfo::new(&req, self.remote_addr))
+        } else {
+            None
+        };
         let lua_req: LuaRequest = LuaRequest::new(req, self.remote_addr);
         let handler = self.handler.clone();
         let exception = self.exception.clone();
         let _router = self.router.clone();
+        let etag = self.etag;
         log::info!(
             "Request -- remote address: {}, method: {}, uri: {}",
             self.remote_addr,
@@ -52,7 +95,8 @@ impl Service<Request<Body>> for Svc {
             path
         );
 
-        Box::pin(async move {
+        let dev = self.dev;
+        let resp: HandlerFuture = Box::pin(async move {
             let handler: Option<LuaFunction> = if let Some(_handler) = handler {
                 Some(lua.registry_value(&_handler)?)
             } else {
@@ -69,97 +113,161 @@ impl Service<Request<Body>> for Svc {
                         .execute(method, path, lua_req, exception.clone(), handler)
                         .await
                     {
-                        Ok(lua_resp) => match lua_resp {
+                        Ok((lua_resp, options)) => match lua_resp {
                             LuaValue::UserData(v) => {
                                 let resp = v.take::<HiveResponse<Body>>()?;
-                                Ok(resp.0)
+                                Ok((resp.0, options))
                             }
                             _ => {
                                 let body = serde_json::to_vec(&lua_resp)?;
                                 let resp = Response::new(Body::from(body));
-                                Ok(resp)
+                                Ok((resp, options))
                             }
                         },
                         Err(err) => {
-                            // println!("{err:?}");
-
-                            // let (code, message) = return_err_info(err);
-                            log::error!("{}", err.message);
-                            let resp = exception
-                                .call_async::<_, LuaValue>((err.code, err.message))
-                                .await?;
-                            match resp {
-                                LuaValue::UserData(v) => {
-                                    let resp = v.take::<HiveResponse<Body>>()?;
-                                    Ok(resp.0)
+                            let debug_info = debug_info.map(|mut info| {
+                                if let Some((route, params)) =
+                                    router.route_info(&info.method, &info.path)
+                                {
+                                    info.route = Some(route);
+                                    info.route_params = params;
                                 }
-                                _ => {
-                                    let body = serde_json::to_vec(&resp)?;
-                                    let resp = Response::new(Body::from(body));
-                                    Ok(resp)
-                                }
-                            }
+                                info
+                            });
+                            let resp = handle_error(&lua, exception, err, debug_info).await?;
+                            Ok((resp, RouteOptions::default()))
                         }
                     }
                 } else {
-                    Ok(Response::new(Body::empty()))
+                    Ok((Response::new(Body::empty()), RouteOptions::default()))
                 }
             }
             #[cfg(feature = "lua_hotfix")]
             if let Some(handler) = handler {
-                match handler.call_async((method, path, lua_req)).await {
+                // 路由在lua中匹配，路由选项和路由参数从router:execute中取
+                let (result, matched) =
+                    router::scope(handler.call_async((method, path, lua_req))).await;
+                let options = matched.options;
+                notify::verify_reload(&lua, result.as_ref().err());
+                match result {
                     Ok(lua_resp) => match lua_resp {
                         LuaValue::UserData(v) => {
                             let resp = v.take::<HiveResponse<Body>>()?;
-                            Ok(resp.0)
+                            Ok((resp.0, options))
                         }
                         _ => {
                             let body = serde_json::to_vec(&lua_resp)?;
                             let resp = Response::new(Body::from(body));
-                            Ok(resp)
+                            Ok((resp, options))
                         }
                     },
                     Err(err) => {
                         println!("{err:?}");
 
-                        let (code, message) = return_err_info(err);
-                        log::error!("{}", message);
-                        let resp = exception.call_async::<_, LuaValue>((code, message)).await?;
-                        match resp {
-                            LuaValue::UserData(v) => {
-                                let resp = v.take::<HiveResponse<Body>>()?;
-                                Ok(resp.0)
-                            }
-                            _ => {
-                                let body = serde_json::to_vec(&resp)?;
-                                let resp = Response::new(Body::from(body));
-                                Ok(resp)
-                            }
-                        }
+                        let debug_info = debug_info.map(|mut info| {
+                            info.route = matched.route;
+                            info.route_params = matched.params;
+                            info
+                        });
+                        let resp = handle_error(&lua, exception, err, debug_info).await?;
+                        Ok((resp, RouteOptions::default()))
                     }
                 }
             } else {
-                Ok(Response::new(Body::empty()))
+                Ok((Response::new(Body::empty()), RouteOptions::default()))
+            }
+        });
+
+        // dev模式下记录这个请求执行过的sql
+        let resp: HandlerFuture = if dev {
+            Box::pin(query_log::scope(resp))
+        } else {
+            resp
+        };
+
+        Box::pin(async move {
+            let (resp, options) = resp.await?;
+            #[cfg(feature = "lua_hotfix")]
+            let resp = if let Some(method) = inject_livereload {
+                livereload::inject(&method, resp).await?
+            } else {
+                resp
+            };
+            let etag = options.etag.unwrap_or(etag);
+            let resp = conditional.apply(resp, etag).await?;
+            if let Some(cors) = cors {
+                Ok(cors.apply(&cors_lua, origin, resp).await)
+            } else {
+                Ok(resp)
             }
         })
     }
 }
 
-#[cfg(feature = "lua_hotfix")]
-fn return_err_info(err: LuaError) -> (u16, String) {
+// 调用异常处理函数，参数依次为：code, message, status, data
+async fn call_exception<'lua>(
+    lua: &'lua Lua,
+    exception: LuaFunction<'lua>,
+    err: WebError,
+) -> Result<Response<Body>, WebError> {
+    let data: LuaValue = match err.data {
+        Some(ref data) => lua.to_value(data)?,
+        None => LuaValue::Nil,
+    };
+    let resp = exception
+        .call_async::<_, LuaValue>((err.code, err.message, err.status, data))
+        .await?;
+    match resp {
+        LuaValue::UserData(v) => {
+            let resp = v.take::<HiveResponse<Body>>()?;
+            Ok(resp.0)
+        }
+        _ => {
+            let body = serde_json::to_vec(&resp)?;
+            let mut resp = Response::new(Body::from(body));
+            if let Some(status) = err.status.and_then(|v| StatusCode::from_u16(v).ok()) {
+                *resp.status_mut() = status;
+            }
+            Ok(resp)
+        }
+    }
+}
+
+// hive.web_error抛出的错误交给异常处理函数，其他错误在dev模式下显示调试页面
+async fn handle_error<'lua>(
+    lua: &'lua Lua,
+    exception: LuaFunction<'lua>,
+    err: LuaError,
+    debug_info: Option<DebugInfo>,
+) -> Result<Response<Body>, WebError> {
+    if let Some(err) = WebError::from_lua_error(&err) {
+        return call_exception(lua, exception, err).await;
+    }
+    if let Some(mut info) = debug_info {
+        log::error!("{err}");
+        info.queries = query_log::records();
+        return Ok(debug_page(info, &err).await);
+    }
+    call_exception(lua, exception, return_err_info(err)).await
+}
+
+fn return_err_info(err: LuaError) -> WebError {
+    if let Some(err) = WebError::from_lua_error(&err) {
+        return err;
+    }
     match err {
         LuaError::SyntaxError {
             message,
             incomplete_input: _,
-        } => (4005, message),
-        LuaError::RuntimeError(v) => (4006, v),
-        LuaError::MemoryError(v) => (4007, v),
-        LuaError::SafetyError(v) => (4009, v),
+        } => WebError::new(4005, message),
+        LuaError::RuntimeError(v) => WebError::new(4006, v),
+        LuaError::MemoryError(v) => WebError::new(4007, v),
+        LuaError::SafetyError(v) => WebError::new(4009, v),
         LuaError::ToLuaConversionError {
             from: _,
             to: _,
             message,
-        } => (
+        } => WebError::new(
             4010,
             message.unwrap_or_else(|| "To Lua Conversion Error".to_string()),
         ),
@@ -167,16 +275,16 @@ fn return_err_info(err: LuaError) -> (u16, String) {
             from: _,
             to: _,
             message,
-        } => (
+        } => WebError::new(
             4011,
             message.unwrap_or_else(|| "From Lua Conversion Error".to_string()),
         ),
-        LuaError::MetaMethodRestricted(v) => (4012, v),
+        LuaError::MetaMethodRestricted(v) => WebError::new(4012, v),
         LuaError::MetaMethodTypeError {
             method: _,
             type_name: _,
             message,
-        } => (
+        } => WebError::new(
             4013,
             message.unwrap_or_else(|| "Meta Method Type Error".to_string()),
         ),
@@ -187,18 +295,10 @@ fn return_err_info(err: LuaError) -> (u16, String) {
             let err = cause.as_ref();
             return_err_info(err.clone())
         }
-        LuaError::SerializeError(v) => (4015, v),
-        LuaError::DeserializeError(v) => (4016, v),
-        LuaError::ExternalError(v) => {
-            let s = v.as_ref().to_string();
-            let r: Vec<&str> = s.split(',').collect();
-            if r.len() >= 2 {
-                (r[0].parse::<u16>().unwrap_or(500u16), r[1].to_string())
-            } else {
-                (500, s)
-            }
-        }
-        _ => (4017, err.to_string()),
+        LuaError::SerializeError(v) => WebError::new(4015, v),
+        LuaError::DeserializeError(v) => WebError::new(4016, v),
+        LuaError::ExternalError(v) => WebError::new(500, v.to_string()),
+        _ => WebError::new(4017, err.to_string()),
     }
 }
 
@@ -207,6 +307,11 @@ pub struct MakeSvc {
     pub handler: Option<Arc<LuaRegistryKey>>,
     pub exception: Arc<LuaRegistryKey>,
     pub router: Option<Arc<HiveRouter>>,
+    pub etag: bool,
+    pub cors: Option<Arc<Cors>>,
+    pub dev: bool,
+    #[cfg(feature = "lua_hotfix")]
+    pub livereload: bool,
 }
 
 impl Service<&AddrStream> for MakeSvc {
@@ -229,6 +334,11 @@ impl Service<&AddrStream> for MakeSvc {
         let exception = self.exception.clone();
         let remote_addr = stream.remote_addr();
         let router = self.router.clone();
+        let etag = self.etag;
+        let cors = self.cors.clone();
+        let dev = self.dev;
+        #[cfg(feature = "lua_hotfix")]
+        let livereload = self.livereload;
 
         #[cfg(feature = "h2")]
         {
@@ -243,6 +353,11 @@ impl Service<&AddrStream> for MakeSvc {
                     handler,
                     exception,
                     router,
+                    etag,
+                    cors,
+                    dev,
+                    #[cfg(feature = "lua_hotfix")]
+                    livereload,
                 })
             })
         }
@@ -0,0 +1,329 @@
+use crate::error::Error as WebError;
+use mlua::prelude::*;
+use std::sync::Arc;
+
+// 只有一个参数，并且是带字符串key的table时使用命名参数
+pub fn named_table<'lua>(params: &LuaMultiValue<'lua>) -> LuaResult<Option<LuaTable<'lua>>> {
+    if params.len() != 1 {
+        return Ok(None);
+    }
+    match params.iter().next() {
+        Some(LuaValue::Table(table)) => is_named(table).map(|named| named.then(|| table.clone())),
+        _ => Ok(None),
+    }
+}
+
+pub fn is_named(table: &LuaTable) -> LuaResult<bool> {
+    if table.raw_len() > 0 {
+        return Ok(false);
+    }
+    for pair in table.clone().pairs::<LuaValue, LuaValue>() {
+        let (key, _) = pair?;
+        if let LuaValue::String(_) = key {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// 不同数据库的注释、字符串和占位符写法不同
+// 只开启其中一个数据库的feature时另一个不会被使用
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    // #注释，字符串中\是转义符，占位符为?，数组参数展开成多个占位符
+    Mysql,
+    // $tag$字符串，只有E'...'中\是转义符，占位符为$1，数组参数按数组类型传递
+    Postgres,
+}
+
+impl Dialect {
+    fn placeholder(self, n: usize) -> String {
+        match self {
+            Dialect::Mysql => String::from("?"),
+            Dialect::Postgres => format!("${n}"),
+        }
+    }
+
+    // postgres的#是运算符，#>和#>>用于json
+    fn is_comment(self, c: char) -> bool {
+        self == Dialect::Mysql && c == '#'
+    }
+
+    fn is_quote(self, c: char) -> bool {
+        c == '\'' || c == '"' || (self == Dialect::Mysql && c == '`')
+    }
+
+    // postgres默认standard_conforming_strings，只有E'...'中可以用\转义
+    fn backslash_escapes(self, chars: &[char], start: usize) -> bool {
+        match self {
+            Dialect::Mysql => chars[start] != '`',
+            Dialect::Postgres => {
+                chars[start] == '\''
+                    && start > 0
+                    && matches!(chars[start - 1], 'E' | 'e')
+                    && (start == 1 || !is_ident(chars[start - 2]))
+            }
+        }
+    }
+}
+
+fn param_error(msg: String) -> LuaError {
+    LuaError::ExternalError(Arc::new(WebError::new(6023, msg)))
+}
+
+// 跳过引号中的内容，返回结束引号之后的位置
+fn skip_quoted(chars: &[char], start: usize, backslash: bool) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        if backslash && chars[i] == '\\' {
+            i += 2;
+            continue;
+        }
+        if chars[i] == quote {
+            // 两个引号表示一个引号
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+fn skip_until(chars: &[char], start: usize, end: &str) -> usize {
+    let end: Vec<char> = end.chars().collect();
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i..].starts_with(&end) {
+            return i + end.len();
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+// postgres的$tag$字符串，返回结束标记之后的位置，不是字符串时返回None
+fn skip_dollar_quoted(chars: &[char], start: usize) -> Option<usize> {
+    let mut end = start + 1;
+    while end < chars.len() && is_ident(chars[end]) {
+        end += 1;
+    }
+    if chars.get(end) != Some(&'$') {
+        return None;
+    }
+    let tag: String = chars[start..=end].iter().collect();
+    Some(skip_until(chars, end + 1, &tag))
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// 字符串和注释的结束位置，不是字符串和注释时返回None
+fn skip_literal(chars: &[char], i: usize, dialect: Dialect) -> Option<usize> {
+    let next = chars.get(i + 1).copied();
+    match chars[i] {
+        c if dialect.is_quote(c) => {
+            Some(skip_quoted(chars, i, dialect.backslash_escapes(chars, i)))
+        }
+        '-' if next == Some('-') => Some(skip_until(chars, i, "\n")),
+        c if dialect.is_comment(c) => Some(skip_until(chars, i, "\n")),
+        '/' if next == Some('*') => Some(skip_until(chars, i + 2, "*/")),
+        // $1这样的占位符不是字符串
+        '$' if dialect == Dialect::Postgres
+            && (i == 0 || !is_ident(chars[i - 1]))
+            && (next == Some('$') || next.map(is_ident_start).unwrap_or(false)) =>
+        {
+            skip_dollar_quoted(chars, i)
+        }
+        _ => None,
+    }
+}
+
+// postgres的sql中用到的$1、$2参数个数
+#[cfg(feature = "postgres")]
+pub fn positional_count(sql: &str) -> usize {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut count: usize = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(end) = skip_literal(&chars, i, Dialect::Postgres) {
+            i = end;
+            continue;
+        }
+        let mut end = i + 1;
+        if chars[i] == '$' && (i == 0 || !is_ident(chars[i - 1])) {
+            while end < chars.len() && chars[end].is_ascii_digit() {
+                end += 1;
+            }
+            let index: String = chars[i + 1..end].iter().collect();
+            count = count.max(index.parse().unwrap_or(0));
+        }
+        i = end;
+    }
+    count
+}
+
+// 把:name改写成占位符，mysql的数组参数展开成多个占位符，空数组改写成NULL
+// 例如：WHERE id IN (:ids) AND name = :name -> WHERE id IN (?, ?, ?) AND name = ?
+pub fn bind_named<T>(
+    sql: &str,
+    params: &LuaTable,
+    dialect: Dialect,
+    mut convert: impl FnMut(LuaValue) -> LuaResult<T>,
+) -> LuaResult<(String, Vec<T>)> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut new_sql = String::with_capacity(sql.len());
+    let mut values: Vec<T> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(end) = skip_literal(&chars, i, dialect) {
+            new_sql.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        let next = chars.get(i + 1).copied();
+        let end = match chars[i] {
+            // ::和:=不是参数
+            ':' if next.map(is_ident_start).unwrap_or(false) && (i == 0 || chars[i - 1] != ':') => {
+                let mut end = i + 1;
+                while end < chars.len() && is_ident(chars[end]) {
+                    end += 1;
+                }
+                let name: String = chars[i + 1..end].iter().collect();
+                match params.get::<_, LuaValue>(name.as_str())? {
+                    LuaValue::Nil => {
+                        return Err(param_error(format!("Missing named parameter: {name}")))
+                    }
+                    LuaValue::Table(array) if dialect == Dialect::Mysql => {
+                        let len = array.raw_len();
+                        if len == 0 {
+                            new_sql.push_str("NULL");
+                        }
+                        for j in 1..=len {
+                            if j > 1 {
+                                new_sql.push_str(", ");
+                            }
+                            values.push(convert(array.raw_get(j)?)?);
+                            new_sql.push_str(&dialect.placeholder(values.len()));
+                        }
+                    }
+                    value => {
+                        values.push(convert(value)?);
+                        new_sql.push_str(&dialect.placeholder(values.len()));
+                    }
+                }
+                i = end;
+                continue;
+            }
+            _ => i + 1,
+        };
+        new_sql.extend(&chars[i..end]);
+        i = end;
+    }
+    Ok((new_sql, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 参数转换成字符串，方便比较
+    fn convert(value: LuaValue) -> LuaResult<String> {
+        Ok(match value {
+            LuaValue::Integer(n) => n.to_string(),
+            LuaValue::Table(_) => String::from("table"),
+            value => value.type_name().to_string(),
+        })
+    }
+
+    fn bind(sql: &str, params: &str, dialect: Dialect) -> (String, Vec<String>) {
+        let lua = Lua::new();
+        let params: LuaTable = lua.load(params).eval().unwrap();
+        bind_named(sql, &params, dialect, convert).unwrap()
+    }
+
+    #[test]
+    fn mysql_expands_arrays() {
+        let (sql, values) = bind(
+            "SELECT * FROM t WHERE id IN (:ids) AND a = :a AND b IN (:empty)",
+            "{ ids = { 1, 2, 3 }, a = 4, empty = {} }",
+            Dialect::Mysql,
+        );
+        assert_eq!(
+            sql,
+            "SELECT * FROM t WHERE id IN (?, ?, ?) AND a = ? AND b IN (NULL)"
+        );
+        assert_eq!(values, ["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn mysql_skips_comments_and_quotes() {
+        let (sql, values) = bind(
+            "SELECT ':x', 'it\\'s :x', `:x`, \":x\" -- :x\n# :x\n/* :x */ FROM t WHERE a = :a",
+            "{ a = 1 }",
+            Dialect::Mysql,
+        );
+        assert_eq!(
+            sql,
+            "SELECT ':x', 'it\\'s :x', `:x`, \":x\" -- :x\n# :x\n/* :x */ FROM t WHERE a = ?"
+        );
+        assert_eq!(values, ["1"]);
+    }
+
+    #[test]
+    fn postgres_keeps_arrays_and_casts() {
+        let (sql, values) = bind(
+            "SELECT :a::int, data #> '{x}', data #>> :path FROM t WHERE id = ANY(:ids)",
+            "{ a = 1, path = 2, ids = { 3, 4 } }",
+            Dialect::Postgres,
+        );
+        assert_eq!(
+            sql,
+            "SELECT $1::int, data #> '{x}', data #>> $2 FROM t WHERE id = ANY($3)"
+        );
+        assert_eq!(values, ["1", "2", "table"]);
+    }
+
+    #[test]
+    fn postgres_skips_strings() {
+        let (sql, values) = bind(
+            "SELECT 'a\\' || :a, E'b\\' :x', $$ :x $$, $tag$ ' :x $tag$, \":x\" -- :x\n/* :x */ FROM t WHERE b = :b",
+            "{ a = 1, b = 2 }",
+            Dialect::Postgres,
+        );
+        assert_eq!(
+            sql,
+            "SELECT 'a\\' || $1, E'b\\' :x', $$ :x $$, $tag$ ' :x $tag$, \":x\" -- :x\n/* :x */ FROM t WHERE b = $2"
+        );
+        assert_eq!(values, ["1", "2"]);
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn count_positional_parameters() {
+        assert_eq!(positional_count("SELECT 1"), 0);
+        assert_eq!(positional_count("SELECT * FROM t WHERE id = ANY($1)"), 1);
+        assert_eq!(
+            positional_count("SELECT $2::int, $1, '$3', $$ $4 $$, a$5 -- $6"),
+            2
+        );
+        assert_eq!(positional_count("SELECT $tag$ $3 $tag$, $1 /* $7 */"), 1);
+    }
+
+    #[test]
+    fn missing_parameter() {
+        let lua = Lua::new();
+        let params: LuaTable = lua.load("{ a = 1 }").eval().unwrap();
+        let err = bind_named("SELECT :b", &params, Dialect::Postgres, convert).unwrap_err();
+        assert!(err.to_string().contains("Missing named parameter: b"));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::Error as WebError;
+use mlua::prelude::*;
+use std::cell::Cell;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub fn pool_error(code: u16, msg: &str) -> LuaError {
+    LuaError::ExternalError(Arc::new(WebError::new(code, msg)))
+}
+
+// lua5.4以外的版本不能在for循环的迭代器中yield，异步的__call会报attempt to yield across a C-call boundary
+#[cfg(not(feature = "lua54"))]
+pub fn stream_call_error() -> LuaError {
+    pool_error(
+        6038,
+        "Stream can only be used in a for loop on lua5.4, use `while true do local rows = stream:next() if not rows then break end ... end` instead",
+    )
+}
+
+// 秒数，可以是小数
+pub fn seconds(table: &LuaTable, key: &str) -> LuaResult<Option<Duration>> {
+    match table.get::<_, Option<f64>>(key)? {
+        Some(secs) if secs.is_finite() && secs >= 0.0 => Ok(Some(Duration::from_secs_f64(secs))),
+        Some(_) => Err(LuaError::ExternalError(Arc::new(WebError::new(
+            6028,
+            format!("Invalid {key}"),
+        )))),
+        None => Ok(None),
+    }
+}
+
+// 慢查询的阈值，默认为1秒，false或者0不记录慢查询
+pub fn slow_query(options: Option<&LuaTable>) -> LuaResult<Option<Duration>> {
+    let Some(options) = options else {
+        return Ok(Some(Duration::from_secs(1)));
+    };
+    match options.get::<_, LuaValue>("slow_query")? {
+        LuaValue::Nil => Ok(Some(Duration::from_secs(1))),
+        LuaValue::Boolean(false) => Ok(None),
+        _ => Ok(seconds(options, "slow_query")?.filter(|slow| !slow.is_zero())),
+    }
+}
+
+// hive统计的连接池状态
+pub struct PoolState {
+    pub min: usize,
+    pub max: usize,
+    // 等待空闲连接的时间
+    pub acquire_timeout: Option<Duration>,
+    pub in_use: Cell<usize>,
+    pub acquired: Cell<u64>,
+    pub timeouts: Cell<u64>,
+    pub disconnected: Cell<bool>,
+}
+
+impl PoolState {
+    pub fn new(min: usize, max: usize, acquire_timeout: Option<Duration>) -> Self {
+        PoolState {
+            min,
+            max,
+            acquire_timeout,
+            in_use: Cell::new(0),
+            acquired: Cell::new(0),
+            timeouts: Cell::new(0),
+            disconnected: Cell::new(false),
+        }
+    }
+
+    pub fn stats<'lua>(&self, lua: &'lua Lua) -> LuaResult<LuaTable<'lua>> {
+        let stats: LuaTable = lua.create_table()?;
+        stats.set("min", self.min)?;
+        stats.set("max", self.max)?;
+        stats.set("in_use", self.in_use.get())?;
+        stats.set("acquired", self.acquired.get())?;
+        stats.set("timeouts", self.timeouts.get())?;
+        stats.set("disconnected", self.disconnected.get())?;
+        Ok(stats)
+    }
+}
+
+// 等待空闲连接，设置了acquire_timeout时超时返回错误
+pub async fn acquire<T, E: LuaExternalError>(
+    state: &Rc<PoolState>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> LuaResult<(T, InUse)> {
+    if state.disconnected.get() {
+        return Err(pool_error(6026, "Pool is disconnected"));
+    }
+    let result = match state.acquire_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+            Ok(result) => result,
+            Err(_) => {
+                state.timeouts.set(state.timeouts.get() + 1);
+                return Err(pool_error(6025, "Timed out waiting for a connection"));
+            }
+        },
+        None => fut.await,
+    };
+    let value = result.map_err(|err| err.to_lua_err())?;
+    Ok((value, InUse::new(state.clone())))
+}
+
+// 连接或事务使用期间存在，结束时减少使用中的连接数
+pub struct InUse(Rc<PoolState>);
+
+impl InUse {
+    fn new(state: Rc<PoolState>) -> Self {
+        state.in_use.set(state.in_use.get() + 1);
+        state.acquired.set(state.acquired.get() + 1);
+        InUse(state)
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        self.0.in_use.set(self.0.in_use.get() - 1);
+    }
+}
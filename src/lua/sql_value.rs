@@ -0,0 +1,208 @@
+use crate::error::Error as WebError;
+use crate::lua::datetime::{check_pattern, HiveDateTime, Timezone};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, Timelike};
+use mlua::prelude::*;
+use std::sync::Arc;
+
+// DATE、DATETIME和TIMESTAMP的转换方式
+pub enum DateFormat {
+    Timestamp,
+    String(String),
+    Table,
+    DateTime,
+}
+
+// TIME的转换方式，TIME是时间间隔，可以是负数或者超过24小时
+pub enum TimeFormat {
+    String,
+    Table,
+    Seconds,
+}
+
+// 结果的转换选项，每个连接池单独设置
+pub struct ValueOptions {
+    // NULL转换成hive.null，默认为nil
+    pub null_sentinel: bool,
+    // DECIMAL转换成number，默认为string，不会丢失精度
+    pub decimal_number: bool,
+    pub date: DateFormat,
+    pub time: TimeFormat,
+    // 数据库中的时间所在的时区，应该和数据库连接的time_zone一致
+    pub timezone: Timezone,
+}
+
+impl ValueOptions {
+    pub fn from_table(options: Option<LuaTable>) -> LuaResult<Self> {
+        let mut value_options = ValueOptions {
+            null_sentinel: false,
+            decimal_number: false,
+            date: DateFormat::Timestamp,
+            time: TimeFormat::String,
+            timezone: Timezone::Local,
+        };
+        let Some(options) = options else {
+            return Ok(value_options);
+        };
+        match options.get::<_, LuaValue>("null")? {
+            LuaValue::Nil => {}
+            LuaValue::LightUserData(ud) if ud.0.is_null() => value_options.null_sentinel = true,
+            _ => {
+                return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                    6016,
+                    "The null option must be nil or hive.null",
+                ))))
+            }
+        }
+        if let Some(decimal) = options.get::<_, Option<String>>("decimal")? {
+            value_options.decimal_number = match decimal.as_str() {
+                "string" => false,
+                "number" => true,
+                _ => {
+                    return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                        6017,
+                        "The decimal option must be string or number",
+                    ))))
+                }
+            };
+        }
+        let date: Option<String> = options.get("date")?;
+        let pattern: Option<String> = options.get("date_format")?;
+        value_options.date = match (date.as_deref(), pattern) {
+            (None, None) | (Some("timestamp"), None) => DateFormat::Timestamp,
+            (None, Some(pattern)) | (Some("string"), Some(pattern)) => {
+                check_pattern(&pattern)?;
+                DateFormat::String(pattern)
+            }
+            (Some("string"), None) => DateFormat::String(String::from("%Y-%m-%d %H:%M:%S%.f")),
+            (Some("table"), None) => DateFormat::Table,
+            (Some("datetime"), None) => DateFormat::DateTime,
+            _ => {
+                return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                    6018,
+                    "The date option must be timestamp, string, table or datetime",
+                ))))
+            }
+        };
+        if let Some(time) = options.get::<_, Option<String>>("time")? {
+            value_options.time = match time.as_str() {
+                "string" => TimeFormat::String,
+                "table" => TimeFormat::Table,
+                "seconds" => TimeFormat::Seconds,
+                _ => {
+                    return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                        6019,
+                        "The time option must be string, table or seconds",
+                    ))))
+                }
+            };
+        }
+        if let Some(tz) = options.get::<_, Option<String>>("timezone")? {
+            value_options.timezone = Timezone::parse(&tz)?;
+        }
+        Ok(value_options)
+    }
+
+    pub fn null<'lua>(&self, lua: &'lua Lua) -> LuaValue<'lua> {
+        if self.null_sentinel {
+            lua.null()
+        } else {
+            LuaValue::Nil
+        }
+    }
+}
+
+fn date_table<'lua>(lua: &'lua Lua, naive: &NaiveDateTime) -> LuaResult<LuaValue<'lua>> {
+    let temp: LuaTable = lua.create_table()?;
+    temp.set("year", naive.year())?;
+    temp.set("month", naive.month())?;
+    temp.set("day", naive.day())?;
+    temp.set("hour", naive.hour())?;
+    temp.set("min", naive.minute())?;
+    temp.set("sec", naive.second())?;
+    temp.set("microsecond", naive.nanosecond() / 1000)?;
+    Ok(LuaValue::Table(temp))
+}
+
+// 不带时区的时间，按照timezone选项转换
+pub fn naive_to_lua_value<'lua>(
+    lua: &'lua Lua,
+    naive: NaiveDateTime,
+    options: &ValueOptions,
+) -> LuaResult<LuaValue<'lua>> {
+    if let DateFormat::Table = options.date {
+        return date_table(lua, &naive);
+    }
+    let datetime = options.timezone.resolve(&naive).ok_or_else(|| {
+        LuaError::ExternalError(Arc::new(WebError::new(
+            6021,
+            format!("Invalid date value: {naive}"),
+        )))
+    })?;
+    datetime_to_lua_value(lua, datetime, options)
+}
+
+// 带时区的时间，table和字符串使用datetime本身的时区
+pub fn datetime_to_lua_value<'lua>(
+    lua: &'lua Lua,
+    datetime: DateTime<FixedOffset>,
+    options: &ValueOptions,
+) -> LuaResult<LuaValue<'lua>> {
+    match &options.date {
+        DateFormat::Table => date_table(lua, &datetime.naive_local()),
+        DateFormat::String(pattern) => Ok(LuaValue::String(
+            lua.create_string(&datetime.format(pattern).to_string())?,
+        )),
+        DateFormat::DateTime => Ok(LuaValue::UserData(
+            lua.create_userdata(HiveDateTime(datetime))?,
+        )),
+        // 有微秒时返回小数
+        DateFormat::Timestamp => {
+            let us = datetime.nanosecond() / 1000;
+            if us == 0 {
+                Ok(LuaValue::Integer(datetime.timestamp()))
+            } else {
+                Ok(LuaValue::Number(
+                    datetime.timestamp() as f64 + us as f64 / 1_000_000.0,
+                ))
+            }
+        }
+    }
+}
+
+pub fn time_to_lua_value<'lua>(
+    lua: &'lua Lua,
+    (negative, days, h, m, s, us): (bool, u32, u8, u8, u8, u32),
+    options: &ValueOptions,
+) -> LuaResult<LuaValue<'lua>> {
+    let hours: u32 = days * 24 + h as u32;
+    match options.time {
+        TimeFormat::String => {
+            let sign = if negative { "-" } else { "" };
+            let mut time: String = format!("{sign}{hours:02}:{m:02}:{s:02}");
+            if us != 0 {
+                time.push_str(&format!(".{us:06}"));
+            }
+            Ok(LuaValue::String(lua.create_string(&time)?))
+        }
+        TimeFormat::Table => {
+            let temp: LuaTable = lua.create_table()?;
+            temp.set("negative", negative)?;
+            temp.set("hour", hours)?;
+            temp.set("min", m)?;
+            temp.set("sec", s)?;
+            temp.set("microsecond", us)?;
+            Ok(LuaValue::Table(temp))
+        }
+        TimeFormat::Seconds => {
+            let sign: i64 = if negative { -1 } else { 1 };
+            let secs: i64 = hours as i64 * 3600 + m as i64 * 60 + s as i64;
+            if us == 0 {
+                Ok(LuaValue::Integer(sign * secs))
+            } else {
+                Ok(LuaValue::Number(
+                    sign as f64 * (secs as f64 + us as f64 / 1_000_000.0),
+                ))
+            }
+        }
+    }
+}
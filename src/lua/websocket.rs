@@ -1,16 +1,294 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use super::deflate::{DeflateConfig, WSStream};
 use super::ws::WSMessage;
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
+use crate::error::Error as WebError;
+use futures_channel::mpsc::{channel, Sender};
+use futures_util::{future, pin_mut, StreamExt};
+use http::HeaderValue;
 use hyper::upgrade::Upgraded;
 use mlua::prelude::*;
 use once_cell::sync::Lazy;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::time::Instant;
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Message,
+    },
+    WebSocketStream,
+};
 
-type Tx = UnboundedSender<Message>;
+type Tx = Sender<Message>;
 
-static SUBSCRIBERS: Lazy<Mutex<HashMap<SocketAddr, Tx>>> = Lazy::new(Default::default);
+// 发送队列满时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    // 丢弃新消息
+    Drop,
+    // 断开连接
+    Disconnect,
+}
+
+struct Peer {
+    sender: Tx,
+    overflow: Overflow,
+    // 协商出的子协议
+    protocol: Option<String>,
+}
+
+impl Peer {
+    // 返回false表示发送队列已满并且需要断开连接
+    fn send(&mut self, addr: &SocketAddr, msg: Message) -> LuaResult<bool> {
+        match self.sender.try_send(msg) {
+            Ok(()) => Ok(true),
+            Err(err) if err.is_full() => match self.overflow {
+                Overflow::Drop => {
+                    log::warn!("websocket {addr} send queue is full, message dropped");
+                    Ok(true)
+                }
+                Overflow::Disconnect => {
+                    log::warn!("websocket {addr} send queue is full, disconnect");
+                    Ok(false)
+                }
+            },
+            Err(err) => Err(err.into_send_error()).to_lua_err(),
+        }
+    }
+
+    // 先发送close帧，再关闭发送通道
+    fn close(mut self) {
+        let _ = self.sender.try_send(Message::Close(None));
+        self.sender.close_channel();
+        self.sender.disconnect();
+    }
+}
+
+static SUBSCRIBERS: Lazy<Mutex<HashMap<SocketAddr, Peer>>> = Lazy::new(Default::default);
+
+fn peer_not_found(addr: &SocketAddr) -> LuaError {
+    LuaError::ExternalError(Arc::new(WebError::new(
+        5053,
+        format!("websocket peer not found: {addr}"),
+    )))
+}
+
+// 给已经取得锁的连接列表发送消息，发送队列已满时按照overflow处理
+fn deliver(
+    subscribers: &mut HashMap<SocketAddr, Peer>,
+    addr: &SocketAddr,
+    msg: Message,
+) -> LuaResult<()> {
+    let peer = subscribers
+        .get_mut(addr)
+        .ok_or_else(|| peer_not_found(addr))?;
+    if !peer.send(addr, msg)? {
+        if let Some(peer) = subscribers.remove(addr) {
+            leave_all_rooms(addr);
+            peer.close();
+        }
+    }
+    Ok(())
+}
+
+fn send_to_peer(addr: &SocketAddr, msg: Message) -> LuaResult<()> {
+    deliver(&mut SUBSCRIBERS.lock().unwrap(), addr, msg)
+}
+
+fn send_to_all(msg: Message) -> LuaResult<()> {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    let addrs: Vec<SocketAddr> = subscribers.keys().copied().collect();
+    for addr in addrs {
+        deliver(&mut subscribers, &addr, msg.clone())?;
+    }
+    Ok(())
+}
+
+fn remove_peer(addr: &SocketAddr) -> LuaResult<()> {
+    let peer = SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .remove(addr)
+        .ok_or_else(|| peer_not_found(addr))?;
+    leave_all_rooms(addr);
+    peer.close();
+    Ok(())
+}
+
+// upgrade的选项，时间单位为秒，例如：
+// { ping_interval = 30, pong_timeout = 10, idle_timeout = 300, send_queue = 64, on_overflow = 'disconnect' }
+#[derive(Clone, Copy, Debug)]
+pub struct WSOptions {
+    ping_interval: Option<Duration>,
+    pong_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    send_queue: usize,
+    overflow: Overflow,
+    compression: bool,
+}
+
+fn seconds(options: &LuaTable, key: &str) -> LuaResult<Option<Duration>> {
+    let value: Option<f64> = options.get(key)?;
+    match value {
+        Some(v) if v > 0.0 => Ok(Some(Duration::from_secs_f64(v))),
+        Some(_) => Err(LuaError::RuntimeError(format!(
+            "{key} must be greater than 0"
+        ))),
+        None => Ok(None),
+    }
+}
+
+impl WSOptions {
+    pub fn from_table(options: Option<&LuaTable>) -> LuaResult<Self> {
+        let defaults = WebSocketConfig::default();
+        let options = match options {
+            Some(options) => options,
+            None => {
+                return Ok(WSOptions {
+                    ping_interval: None,
+                    pong_timeout: None,
+                    idle_timeout: None,
+                    max_message_size: defaults.max_message_size,
+                    max_frame_size: defaults.max_frame_size,
+                    send_queue: 1024,
+                    overflow: Overflow::Drop,
+                    compression: false,
+                })
+            }
+        };
+        let ping_interval = seconds(options, "ping_interval")?;
+        let overflow: Option<String> = options.get("on_overflow")?;
+        let overflow = match overflow.as_deref() {
+            None | Some("drop") => Overflow::Drop,
+            Some("disconnect") => Overflow::Disconnect,
+            Some(v) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "on_overflow must be 'drop' or 'disconnect', got '{v}'"
+                )))
+            }
+        };
+        Ok(WSOptions {
+            ping_interval,
+            // 默认等待一个ping间隔
+            pong_timeout: seconds(options, "pong_timeout")?.or(ping_interval),
+            idle_timeout: seconds(options, "idle_timeout")?,
+            max_message_size: options
+                .get::<_, Option<usize>>("max_message_size")?
+                .or(defaults.max_message_size),
+            max_frame_size: options
+                .get::<_, Option<usize>>("max_frame_size")?
+                .or(defaults.max_frame_size),
+            send_queue: options
+                .get::<_, Option<usize>>("send_queue")?
+                .unwrap_or(1024),
+            overflow,
+            compression: options
+                .get::<_, Option<bool>>("compression")?
+                .unwrap_or(false),
+        })
+    }
+
+    // 开启compression并且客户端支持时协商permessage-deflate
+    pub fn negotiate_deflate(
+        &self,
+        extensions: Option<&HeaderValue>,
+    ) -> Option<(DeflateConfig, HeaderValue)> {
+        if self.compression {
+            super::deflate::negotiate(extensions)
+        } else {
+            None
+        }
+    }
+
+    pub fn stream<S>(&self, inner: S, deflate: Option<DeflateConfig>) -> WSStream<S> {
+        match deflate {
+            Some(config) => {
+                WSStream::with_deflate(inner, config, self.max_frame_size, self.max_message_size)
+            }
+            None => WSStream::new(inner),
+        }
+    }
+
+    pub fn config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_frame_size,
+            ..Default::default()
+        }
+    }
+}
+
+// 按客户端的顺序选出第一个服务端支持的子协议
+pub fn select_protocol(offered: Option<&HeaderValue>, supported: &[String]) -> Option<String> {
+    offered?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(|v| v.trim())
+        .find(|v| supported.iter().any(|p| p == v))
+        .map(|v| v.to_string())
+}
+
+// 房间名 -> 房间内的连接
+static ROOMS: Lazy<Mutex<HashMap<String, HashSet<SocketAddr>>>> = Lazy::new(Default::default);
+
+// 已经断开的连接不能加入房间，否则断开时不会被清理
+fn join_room(room: String, addr: SocketAddr) -> LuaResult<()> {
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    if !subscribers.contains_key(&addr) {
+        return Err(peer_not_found(&addr));
+    }
+    ROOMS.lock().unwrap().entry(room).or_default().insert(addr);
+    Ok(())
+}
+
+// 房间为空时删除房间
+fn leave_room(room: &str, addr: &SocketAddr) {
+    let mut rooms = ROOMS.lock().unwrap();
+    if let Some(members) = rooms.get_mut(room) {
+        members.remove(addr);
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+}
+
+// 断开连接时退出所有房间
+fn leave_all_rooms(addr: &SocketAddr) {
+    ROOMS.lock().unwrap().retain(|_, members| {
+        members.remove(addr);
+        !members.is_empty()
+    });
+}
+
+fn room_members(room: &str) -> Vec<SocketAddr> {
+    ROOMS
+        .lock()
+        .unwrap()
+        .get(room)
+        .map(|members| members.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+// 给房间内的连接发送消息，except为发送者时不发给自己
+fn send_to_room(room: &str, msg: Message, except: Option<SocketAddr>) -> LuaResult<()> {
+    let members = room_members(room);
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    for addr in members {
+        if Some(addr) == except || !subscribers.contains_key(&addr) {
+            continue;
+        }
+        deliver(&mut subscribers, &addr, msg.clone())?;
+    }
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct WSSender {
@@ -22,7 +300,7 @@ impl LuaUserData for WSSender {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
         _methods.add_method_mut("send", |_, this, msg: LuaAnyUserData| {
             let msg = msg.take::<WSMessage>()?;
-            this.sender.unbounded_send(msg.0).to_lua_err()?;
+            this.sender.try_send(msg.0).to_lua_err()?;
             Ok(())
         });
         _methods.add_method_mut("disconnect", |_, this, ()| {
@@ -43,94 +321,619 @@ pub struct WSPeerMap;
 
 impl LuaUserData for WSPeerMap {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
-        _methods.add_function("send_all", |_, msg: LuaAnyUserData| {
+        _methods.add_method("send_all", |_, _, msg: LuaAnyUserData| {
             let msg = msg.take::<WSMessage>()?;
-            let map = SUBSCRIBERS.lock();
-            if let Ok(m) = map {
-                for (_, sender) in m.iter() {
-                    sender.unbounded_send(msg.0.clone()).to_lua_err()?;
-                }
-            }
-            Ok(())
+            send_to_all(msg.0)
         });
-        _methods.add_function(
+        _methods.add_method(
             "send",
-            |_, (addr, msg): (LuaAnyUserData, LuaAnyUserData)| {
+            |_, _, (addr, msg): (LuaAnyUserData, LuaAnyUserData)| {
                 let msg = msg.take::<WSMessage>()?;
                 let addr = addr.borrow::<WSAddr>()?;
-                SUBSCRIBERS
-                    .lock()
-                    .unwrap()
-                    .get(&addr.0)
-                    .unwrap()
-                    .unbounded_send(msg.0)
-                    .to_lua_err()?;
-                Ok(())
+                send_to_peer(&addr.0, msg.0)
             },
         );
-        _methods.add_function("remove", |_, addr: LuaAnyUserData| {
+        _methods.add_method("remove", |_, _, addr: LuaAnyUserData| {
             let addr = addr.borrow::<WSAddr>()?;
-            let mut sender = SUBSCRIBERS.lock().unwrap().remove(&addr.0).unwrap();
-            sender.disconnect();
-            sender.close_channel();
+            remove_peer(&addr.0)
+        });
+        _methods.add_method("join", |_, _, (room, addr): (String, LuaAnyUserData)| {
+            join_room(room, addr.borrow::<WSAddr>()?.0)
+        });
+        _methods.add_method("leave", |_, _, (room, addr): (String, LuaAnyUserData)| {
+            leave_room(&room, &addr.borrow::<WSAddr>()?.0);
             Ok(())
         });
+        // 给房间内所有连接发送消息，传入except时跳过该连接
+        _methods.add_method(
+            "send_room",
+            |_, _, (room, msg, except): (String, LuaAnyUserData, Option<LuaAnyUserData>)| {
+                let msg = msg.take::<WSMessage>()?;
+                let except = match except {
+                    Some(v) => Some(v.borrow::<WSAddr>()?.0),
+                    None => None,
+                };
+                send_to_room(&room, msg.0, except)
+            },
+        );
+        _methods.add_method("members", |_, _, room: String| {
+            let members: Vec<WSAddr> = room_members(&room).into_iter().map(WSAddr).collect();
+            Ok(members)
+        });
+        _methods.add_method("count", |_, _, room: String| {
+            Ok(ROOMS
+                .lock()
+                .unwrap()
+                .get(&room)
+                .map(|v| v.len())
+                .unwrap_or(0))
+        });
+        _methods.add_method("rooms", |_, _, ()| {
+            let rooms: Vec<String> = ROOMS.lock().unwrap().keys().cloned().collect();
+            Ok(rooms)
+        });
     }
 }
 
-pub struct WSAddr(SocketAddr);
+#[derive(Clone, Copy)]
+pub struct WSAddr(pub SocketAddr);
 
 impl LuaUserData for WSAddr {
-    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
-        _methods.add_method("send", |_, this, msg: LuaAnyUserData| {
-            let msg = msg.take::<WSMessage>()?;
-            SUBSCRIBERS
+    // 回调函数收到的连接对象，state在整个连接期间保持不变，可以保存登录用户等信息
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_function_get("state", |_, this: LuaAnyUserData| {
+            this.get_user_value::<LuaValue>()
+        });
+        fields.add_field_function_set("state", |_, this: LuaAnyUserData, state: LuaValue| {
+            this.set_user_value(state)
+        });
+        // 协商出的子协议，没有时为nil
+        fields.add_field_method_get("protocol", |_, this| {
+            Ok(SUBSCRIBERS
                 .lock()
                 .unwrap()
                 .get(&this.0)
-                .unwrap()
-                .unbounded_send(msg.0)
-                .to_lua_err()?;
-            Ok(())
+                .and_then(|peer| peer.protocol.clone()))
+        });
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_method("send", |_, this, msg: LuaAnyUserData| {
+            let msg = msg.take::<WSMessage>()?;
+            send_to_peer(&this.0, msg.0)
         });
-        _methods.add_method("disconnect", |_, this, ()| {
-            let mut sender = SUBSCRIBERS.lock().unwrap().remove(&this.0).unwrap();
-            sender.disconnect();
-            sender.close_channel();
+        _methods.add_method("disconnect", |_, this, ()| remove_peer(&this.0));
+        _methods.add_method("addr", |_, this, ()| Ok(this.0.to_string()));
+        _methods.add_method("join", |_, this, room: String| join_room(room, this.0));
+        _methods.add_method("leave", |_, this, room: String| {
+            leave_room(&room, &this.0);
             Ok(())
         });
+        // 给房间内除自己以外的连接发送消息
+        _methods.add_method(
+            "broadcast",
+            |_, this, (room, msg): (String, LuaAnyUserData)| {
+                let msg = msg.take::<WSMessage>()?;
+                send_to_room(&room, msg.0, Some(this.0))
+            },
+        );
+        _methods.add_method("rooms", |_, this, ()| {
+            let rooms: Vec<String> = ROOMS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, members)| members.contains(&this.0))
+                .map(|(room, _)| room.clone())
+                .collect();
+            Ok(rooms)
+        });
+    }
+}
+
+// upgrade的回调，传入函数时等同于{ on_message = func }
+pub struct WSHandler<'lua> {
+    on_open: Option<LuaFunction<'lua>>,
+    on_message: Option<LuaFunction<'lua>>,
+    on_close: Option<LuaFunction<'lua>>,
+    on_error: Option<LuaFunction<'lua>>,
+}
+
+impl<'lua> FromLua<'lua> for WSHandler<'lua> {
+    fn from_lua(value: LuaValue<'lua>, _: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Function(f) => Ok(WSHandler {
+                on_open: None,
+                on_message: Some(f),
+                on_close: None,
+                on_error: None,
+            }),
+            LuaValue::Table(t) => Ok(WSHandler {
+                on_open: t.get("on_open")?,
+                on_message: t.get("on_message")?,
+                on_close: t.get("on_close")?,
+                on_error: t.get("on_error")?,
+            }),
+            _ => Err(LuaError::FromLuaConversionError {
+                from: value.type_name(),
+                to: "WSHandler",
+                message: Some("websocket handler must be a function or a table".to_string()),
+            }),
+        }
+    }
+}
+
+impl<'lua> WSHandler<'lua> {
+    // 回调出错时记录日志并交给on_error处理，不会中断连接
+    async fn call<A: ToLuaMulti<'lua>>(
+        &self,
+        func: &Option<LuaFunction<'lua>>,
+        conn: &LuaAnyUserData<'lua>,
+        args: A,
+    ) {
+        let func = match func {
+            Some(func) => func,
+            None => return,
+        };
+        if let Err(err) = func.call_async::<_, ()>(args).await {
+            let addr = conn.borrow::<WSAddr>().map(|v| v.0.to_string());
+            let addr = addr.unwrap_or_default();
+            log::error!("websocket {addr} handler error: {err}");
+            if let Some(ref on_error) = self.on_error {
+                if let Err(err) = on_error
+                    .call_async::<_, ()>((conn.clone(), err.to_string()))
+                    .await
+                {
+                    log::error!("websocket {addr} on_error error: {err}");
+                }
+            }
+        }
     }
 }
 
 pub async fn handle_connection(
-    handler: LuaFunction<'static>,
-    ws_stream: WebSocketStream<Upgraded>,
+    handler: WSHandler<'static>,
+    conn: LuaAnyUserData<'static>,
+    options: WSOptions,
+    protocol: Option<String>,
+    ws_stream: WebSocketStream<WSStream<Upgraded>>,
     addr: SocketAddr,
 ) -> LuaResult<()> {
     // println!("WebSocket connection established: {addr}");
 
-    let (tx, rx) = unbounded::<Message>();
+    let (tx, rx) = channel::<Message>(options.send_queue);
 
-    SUBSCRIBERS.lock().unwrap().insert(addr, tx.clone());
+    SUBSCRIBERS.lock().unwrap().insert(
+        addr,
+        Peer {
+            sender: tx.clone(),
+            overflow: options.overflow,
+            protocol,
+        },
+    );
 
-    let (outgoing, incoming) = ws_stream.split();
+    let (outgoing, mut incoming) = ws_stream.split();
 
     // let sender = WSSender { sender: tx, addr };
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
+    handler
+        .call(&handler.on_open, &conn, (WSPeerMap, conn.clone()))
+        .await;
+
+    // 客户端没有发送close帧时，按1006(异常断开)处理
+    let close_info: RefCell<(u16, String)> = RefCell::new((1006, String::new()));
+    let last_activity: Cell<Instant> = Cell::new(Instant::now());
+    let last_pong: Cell<Instant> = Cell::new(Instant::now());
+    {
+        // 按顺序处理消息
+        let broadcast_incoming = async {
+            while let Some(msg) = incoming.next().await {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        log::error!("websocket {addr} receive error: {err}");
+                        break;
+                    }
+                };
+                // ping/pong不算作活动
+                if !matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+                    last_activity.set(Instant::now());
+                }
+                match msg {
+                    Message::Close(frame) => {
+                        *close_info.borrow_mut() = match frame {
+                            Some(frame) => (frame.code.into(), frame.reason.to_string()),
+                            None => (1005, String::new()),
+                        };
+                        continue;
+                    }
+                    // 开启心跳时，pong由hive处理
+                    Message::Pong(_) if options.ping_interval.is_some() => {
+                        last_pong.set(Instant::now());
+                        continue;
+                    }
+                    _ => {}
+                }
+                handler
+                    .call(
+                        &handler.on_message,
+                        &conn,
+                        (WSPeerMap, conn.clone(), WSMessage(msg)),
+                    )
+                    .await;
+            }
+        };
+
+        // 服务端发送的close帧，on_close收到它的状态码和原因
+        let receive_from_others = rx
+            .inspect(|msg| {
+                if let Message::Close(Some(frame)) = msg {
+                    *close_info.borrow_mut() = (frame.code.into(), frame.reason.to_string());
+                }
+            })
+            .map(Ok)
+            .forward(outgoing);
+
+        // 定时发送ping，超时没有收到pong或者长时间没有消息时断开连接
+        let mut keepalive_tx = tx.clone();
+        let keepalive = async {
+            let period = [
+                options.ping_interval,
+                options.pong_timeout,
+                options.idle_timeout,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let period = match period {
+                Some(period) => period,
+                None => return future::pending::<()>().await,
+            };
+            let mut interval = tokio::time::interval(period);
+            let mut last_ping: Instant = Instant::now();
+            let mut ping_sent: Option<Instant> = None;
+            loop {
+                interval.tick().await;
+                let now = Instant::now();
+                if let Some(idle_timeout) = options.idle_timeout {
+                    if now - last_activity.get() >= idle_timeout {
+                        log::info!("websocket {addr} idle timeout");
+                        *close_info.borrow_mut() = (1001, "idle timeout".to_string());
+                        break;
+                    }
+                }
+                if let (Some(ping_interval), Some(pong_timeout)) =
+                    (options.ping_interval, options.pong_timeout)
+                {
+                    if let Some(sent) = ping_sent {
+                        if last_pong.get() >= sent {
+                            ping_sent = None;
+                        } else if now - sent >= pong_timeout {
+                            // 对方已经没有响应，不再发送close帧
+                            log::info!("websocket {addr} pong timeout");
+                            *close_info.borrow_mut() = (1006, "pong timeout".to_string());
+                            return;
+                        }
+                    }
+                    if ping_sent.is_none() && now - last_ping >= ping_interval {
+                        if keepalive_tx.try_send(Message::Ping(Vec::new())).is_ok() {
+                            ping_sent = Some(now);
+                        }
+                        last_ping = now;
+                    }
+                }
+            }
+            let _ = keepalive_tx.try_send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "idle timeout".into(),
+            })));
+            keepalive_tx.close_channel();
+            // 等待close帧发送完成
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+
+        pin_mut!(broadcast_incoming, receive_from_others, keepalive);
+        future::select(
+            broadcast_incoming,
+            future::select(receive_from_others, keepalive),
+        )
+        .await;
+    }
+
+    // 连接已经被disconnect移除，说明是服务端主动断开
+    let server_closed = SUBSCRIBERS.lock().unwrap().remove(&addr).is_none();
+    leave_all_rooms(&addr);
+    let (mut code, reason) = close_info.into_inner();
+    if server_closed && code == 1006 {
+        code = 1000;
+    }
+    handler
+        .call(
+            &handler.on_close,
+            &conn,
+            (WSPeerMap, conn.clone(), code, reason),
+        )
+        .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_channel::mpsc::Receiver;
+
+    // 连接列表和房间是全局的，每个测试使用不同的端口和房间名
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn add_peer(port: u16, queue: usize, overflow: Overflow) -> Receiver<Message> {
+        let (sender, rx) = channel::<Message>(queue);
+        SUBSCRIBERS.lock().unwrap().insert(
+            addr(port),
+            Peer {
+                sender,
+                overflow,
+                protocol: None,
+            },
+        );
+        rx
+    }
+
+    fn members(room: &str) -> Vec<SocketAddr> {
+        let mut members = room_members(room);
+        members.sort();
+        members
+    }
+
+    fn received(rx: &mut Receiver<Message>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[test]
+    fn join_and_leave_room() {
+        let _rx1 = add_peer(10001, 8, Overflow::Drop);
+        let _rx2 = add_peer(10002, 8, Overflow::Drop);
+        join_room("join_leave".to_string(), addr(10001)).unwrap();
+        join_room("join_leave".to_string(), addr(10002)).unwrap();
+        join_room("join_leave".to_string(), addr(10002)).unwrap();
+        assert_eq!(members("join_leave"), vec![addr(10001), addr(10002)]);
+
+        leave_room("join_leave", &addr(10001));
+        assert_eq!(members("join_leave"), vec![addr(10002)]);
+        // 最后一个连接离开后删除房间
+        leave_room("join_leave", &addr(10002));
+        assert!(!ROOMS.lock().unwrap().contains_key("join_leave"));
+        leave_room("join_leave", &addr(10002));
+    }
+
+    #[test]
+    fn join_unknown_peer() {
+        let err = join_room("join_unknown".to_string(), addr(10011)).unwrap_err();
+        assert!(err.to_string().contains("websocket peer not found"));
+        assert!(members("join_unknown").is_empty());
+    }
+
+    #[test]
+    fn remove_peer_leaves_all_rooms() {
+        let mut rx = add_peer(10021, 8, Overflow::Drop);
+        let _rx = add_peer(10022, 8, Overflow::Drop);
+        join_room("remove_a".to_string(), addr(10021)).unwrap();
+        join_room("remove_b".to_string(), addr(10021)).unwrap();
+        join_room("remove_b".to_string(), addr(10022)).unwrap();
+
+        remove_peer(&addr(10021)).unwrap();
+        assert!(!ROOMS.lock().unwrap().contains_key("remove_a"));
+        assert_eq!(members("remove_b"), vec![addr(10022)]);
+        // 移除时发送close帧
+        assert!(matches!(received(&mut rx)[..], [Message::Close(None)]));
+    }
+
+    #[test]
+    fn broadcast_to_room() {
+        let mut rx1 = add_peer(10031, 8, Overflow::Drop);
+        let mut rx2 = add_peer(10032, 8, Overflow::Drop);
+        let mut rx3 = add_peer(10033, 8, Overflow::Drop);
+        join_room("broadcast".to_string(), addr(10031)).unwrap();
+        join_room("broadcast".to_string(), addr(10032)).unwrap();
+
+        send_to_room("broadcast", Message::text("all"), None).unwrap();
+        send_to_room("broadcast", Message::text("others"), Some(addr(10031))).unwrap();
+        assert_eq!(received(&mut rx1), vec![Message::text("all")]);
+        assert_eq!(
+            received(&mut rx2),
+            vec![Message::text("all"), Message::text("others")]
+        );
+        // 不在房间内的连接收不到消息
+        assert!(received(&mut rx3).is_empty());
+        send_to_room("broadcast_empty", Message::text("none"), None).unwrap();
+    }
+
+    fn error_code(err: &LuaError) -> Option<u16> {
+        WebError::from_lua_error(err).map(|err| err.code)
+    }
+
+    #[test]
+    fn unknown_peer() {
+        let err = send_to_peer(&addr(10101), Message::text("hello")).unwrap_err();
+        assert_eq!(error_code(&err), Some(5053));
+        let err = remove_peer(&addr(10101)).unwrap_err();
+        assert_eq!(error_code(&err), Some(5053));
+
+        let lua = Lua::new();
+        lua.globals().set("conn", WSAddr(addr(10101))).unwrap();
+        let err = lua.load("conn:disconnect()").exec().unwrap_err();
+        assert_eq!(error_code(&err), Some(5053));
+    }
+
+    #[tokio::test]
+    async fn handler_error_goes_to_on_error() {
+        let lua = Lua::new();
+        let handler: WSHandler = lua
+            .load(
+                r#"{
+                    on_message = function(peers, conn, msg) error('boom') end,
+                    on_close = function(peers, conn) error('close failed') end,
+                    on_error = function(conn, err)
+                        errors = errors or {}
+                        errors[#errors + 1] = conn:addr() .. ' ' .. err
+                        if #errors > 1 then error('on_error failed') end
+                    end,
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        let conn = lua.create_userdata(WSAddr(addr(10111))).unwrap();
+
         handler
-            .call::<_, ()>((WSPeerMap, WSAddr(addr), WSMessage(msg)))
+            .call(&handler.on_message, &conn, (WSPeerMap, conn.clone(), "hi"))
+            .await;
+        // on_error出错时只记录日志
+        handler
+            .call(&handler.on_close, &conn, (WSPeerMap, conn.clone()))
+            .await;
+        // 没有设置的回调不调用
+        handler.call(&handler.on_open, &conn, WSPeerMap).await;
+
+        let errors: Vec<String> = lua.load("errors").eval().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("127.0.0.1:10111 "));
+        assert!(errors[0].contains("boom"));
+        assert!(errors[1].contains("close failed"));
+    }
+
+    #[test]
+    fn handler_from_lua() {
+        let lua = Lua::new();
+        let handler: WSHandler = lua.load("function() end").eval().unwrap();
+        assert!(handler.on_message.is_some());
+        assert!(handler.on_open.is_none() && handler.on_close.is_none());
+
+        let handler: WSHandler = lua
+            .load("{ on_open = function() end, on_close = function() end }")
+            .eval()
             .unwrap();
-        future::ok(())
-    });
+        assert!(handler.on_open.is_some() && handler.on_close.is_some());
+        assert!(handler.on_message.is_none() && handler.on_error.is_none());
+
+        assert!(lua.load("123").eval::<WSHandler>().is_err());
+        assert!(lua.load("{ on_open = 1 }").eval::<WSHandler>().is_err());
+    }
+
+    #[test]
+    fn addr_state_and_protocol() {
+        let _rx = add_peer(10201, 8, Overflow::Drop);
+        SUBSCRIBERS
+            .lock()
+            .unwrap()
+            .get_mut(&addr(10201))
+            .unwrap()
+            .protocol = Some("chat".to_string());
+        let lua = Lua::new();
+        let conn = lua.create_userdata(WSAddr(addr(10201))).unwrap();
+        let check: LuaFunction = lua
+            .load(
+                r#"function(conn)
+                    assert(conn.state == nil)
+                    conn.state = { user = 'tom' }
+                    conn.state.count = 1
+                    return conn.state.user .. conn.state.count .. conn.protocol
+                end"#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(check.call::<_, String>(conn.clone()).unwrap(), "tom1chat");
+        // 同一个连接对象的state在回调之间保持不变
+        let state: LuaTable = lua
+            .load("function(conn) return conn.state end")
+            .eval::<LuaFunction>()
+            .unwrap()
+            .call(conn)
+            .unwrap();
+        assert_eq!(state.get::<_, String>("user").unwrap(), "tom");
 
-    let receive_from_others = rx.map(Ok).forward(outgoing);
+        let other = lua.create_userdata(WSAddr(addr(10202))).unwrap();
+        let protocol: LuaValue = lua
+            .load("function(conn) return conn.protocol end")
+            .eval::<LuaFunction>()
+            .unwrap()
+            .call(other)
+            .unwrap();
+        assert_eq!(protocol, LuaValue::Nil);
+        remove_peer(&addr(10201)).unwrap();
+    }
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    #[test]
+    fn options_from_table() {
+        let options = WSOptions::from_table(None).unwrap();
+        assert_eq!(options.send_queue, 1024);
+        assert_eq!(options.overflow, Overflow::Drop);
+        assert!(options.ping_interval.is_none() && options.idle_timeout.is_none());
+        assert!(!options.compression);
 
-    drop(handler);
-    SUBSCRIBERS.lock().unwrap().remove(&addr);
+        let lua = Lua::new();
+        let table = |code: &str| -> LuaTable { lua.load(code).eval().unwrap() };
+        let options = WSOptions::from_table(Some(&table(
+            "{ ping_interval = 30, idle_timeout = 0.5, send_queue = 16, on_overflow = 'disconnect' }",
+        )))
+        .unwrap();
+        assert_eq!(options.ping_interval, Some(Duration::from_secs(30)));
+        // pong_timeout默认等于ping_interval
+        assert_eq!(options.pong_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(options.idle_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(options.send_queue, 16);
+        assert_eq!(options.overflow, Overflow::Disconnect);
 
-    Ok(())
+        let options =
+            WSOptions::from_table(Some(&table("{ ping_interval = 30, pong_timeout = 5 }")))
+                .unwrap();
+        assert_eq!(options.pong_timeout, Some(Duration::from_secs(5)));
+
+        for code in [
+            "{ ping_interval = 0 }",
+            "{ idle_timeout = -1 }",
+            "{ on_overflow = 'block' }",
+        ] {
+            assert!(WSOptions::from_table(Some(&table(code))).is_err(), "{code}");
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_messages() {
+        // channel的容量为queue加上发送者的数量
+        let mut rx = add_peer(10301, 0, Overflow::Drop);
+        join_room("drop".to_string(), addr(10301)).unwrap();
+        send_to_peer(&addr(10301), Message::text("1")).unwrap();
+        send_to_peer(&addr(10301), Message::text("2")).unwrap();
+        send_to_room("drop", Message::text("3"), None).unwrap();
+
+        assert_eq!(received(&mut rx), vec![Message::text("1")]);
+        assert!(SUBSCRIBERS.lock().unwrap().contains_key(&addr(10301)));
+        assert_eq!(members("drop"), vec![addr(10301)]);
+        remove_peer(&addr(10301)).unwrap();
+    }
+
+    #[test]
+    fn full_queue_disconnects() {
+        let mut rx = add_peer(10311, 0, Overflow::Disconnect);
+        let mut other = add_peer(10312, 8, Overflow::Disconnect);
+        join_room("disconnect".to_string(), addr(10311)).unwrap();
+        join_room("disconnect".to_string(), addr(10312)).unwrap();
+        send_to_peer(&addr(10311), Message::text("1")).unwrap();
+        send_to_room("disconnect", Message::text("2"), None).unwrap();
+
+        // 队列已满的连接被移除并退出房间，其它连接不受影响
+        assert!(!SUBSCRIBERS.lock().unwrap().contains_key(&addr(10311)));
+        assert_eq!(members("disconnect"), vec![addr(10312)]);
+        assert_eq!(received(&mut rx), vec![Message::text("1")]);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(received(&mut other), vec![Message::text("2")]);
+        let err = send_to_peer(&addr(10311), Message::text("3")).unwrap_err();
+        assert_eq!(error_code(&err), Some(5053));
+        remove_peer(&addr(10312)).unwrap();
+    }
 }
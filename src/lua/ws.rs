@@ -2,7 +2,10 @@ use crate::error::Error as WebError;
 use mlua::prelude::*;
 use std::{path::Path, sync::Arc};
 use tokio::fs;
-use tungstenite::Message;
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
 
 pub struct WSMessage(pub Message);
 
@@ -12,6 +15,57 @@ impl LuaUserData for WSMessage {
             let msg = Message::text(text);
             Ok(WSMessage(msg))
         });
+        _methods.add_function("binary", |_, bytes: LuaString| {
+            Ok(WSMessage(Message::binary(bytes.as_bytes())))
+        });
+        _methods.add_function("ping", |_, data: Option<LuaString>| {
+            let data = data.map(|v| v.as_bytes().to_vec()).unwrap_or_default();
+            Ok(WSMessage(Message::Ping(data)))
+        });
+        _methods.add_function("pong", |_, data: Option<LuaString>| {
+            let data = data.map(|v| v.as_bytes().to_vec()).unwrap_or_default();
+            Ok(WSMessage(Message::Pong(data)))
+        });
+        // 不传code时发送不带状态码的close帧，1005、1006、1015等不能发送的状态码报错
+        _methods.add_function(
+            "close",
+            |_, (code, reason): (Option<u16>, Option<String>)| {
+                let frame = match code {
+                    Some(code) if !CloseCode::from(code).is_allowed() => {
+                        return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                            5057,
+                            format!("Invalid Close Code: {code}"),
+                        ))))
+                    }
+                    Some(code) => Some(CloseFrame {
+                        code: CloseCode::from(code),
+                        reason: reason.unwrap_or_default().into(),
+                    }),
+                    None => None,
+                };
+                Ok(WSMessage(Message::Close(frame)))
+            },
+        );
+        // 把lua值编码成text消息
+        _methods.add_function("json", |_, value: LuaValue| {
+            let text = serde_json::to_string(&value).to_lua_err()?;
+            Ok(WSMessage(Message::text(text)))
+        });
+        // 把收到的text或者binary消息解析成lua值
+        _methods.add_method("decode_json", |lua, this, ()| {
+            let data: &[u8] = match this.0 {
+                Message::Text(ref text) => text.as_bytes(),
+                Message::Binary(ref data) => data,
+                _ => &[],
+            };
+            let value: serde_json::Value = serde_json::from_slice(data).map_err(|e| {
+                LuaError::ExternalError(Arc::new(WebError::new(
+                    5056,
+                    format!("Invalid JSON Message: {e}"),
+                )))
+            })?;
+            lua.to_value(&value)
+        });
         _methods.add_async_function("binary_from_file_path", |_, path: String| async move {
             let bin = fs::read(Path::new(&path)).await;
             if let Ok(binary) = bin {
@@ -29,6 +83,15 @@ impl LuaUserData for WSMessage {
         _methods.add_method("is_ping", |_, this, ()| Ok(this.0.is_ping()));
         _methods.add_method("is_pong", |_, this, ()| Ok(this.0.is_pong()));
         _methods.add_method("is_close", |_, this, ()| Ok(this.0.is_close()));
+        // 收到close帧时的状态码和原因，没有时为nil
+        _methods.add_method("close_code", |_, this, ()| match this.0 {
+            Message::Close(Some(ref frame)) => Ok(Some(u16::from(frame.code))),
+            _ => Ok(None),
+        });
+        _methods.add_method("close_reason", |_, this, ()| match this.0 {
+            Message::Close(Some(ref frame)) => Ok(Some(frame.reason.to_string())),
+            _ => Ok(None),
+        });
         _methods.add_method("len", |_, this, ()| Ok(this.0.len()));
         _methods.add_method("is_empty", |_, this, ()| Ok(this.0.is_empty()));
         _methods.add_function("into_data", |_, this: LuaAnyUserData| {
@@ -66,3 +129,73 @@ impl LuaUserData for WSMessage {
 pub fn create_message(lua: &Lua) -> LuaResult<LuaAnyUserData> {
     lua.create_proxy::<WSMessage>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> Lua {
+        let lua = Lua::new();
+        lua.globals()
+            .set("ws_message", create_message(&lua).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let lua = lua();
+        let msg: LuaAnyUserData = lua
+            .load("ws_message.json({ name = 'hive', ids = { 1, 2 } })")
+            .eval()
+            .unwrap();
+        let text = msg
+            .borrow::<WSMessage>()
+            .unwrap()
+            .0
+            .to_text()
+            .unwrap()
+            .to_string();
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value, serde_json::json!({ "name": "hive", "ids": [1, 2] }));
+
+        lua.globals().set("msg", msg).unwrap();
+        lua.load(
+            r#"
+            local value = msg:decode_json()
+            assert(value.name == 'hive' and value.ids[2] == 2)
+            assert(ws_message.binary('[1, 2]'):decode_json()[2] == 2)
+            -- json只用来创建消息，解析收到的消息使用decode_json
+            assert(not pcall(ws_message.json, msg))
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let err = lua
+            .load("ws_message.text('{'):decode_json()")
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid JSON Message"));
+    }
+
+    #[test]
+    fn close_codes() {
+        let lua = lua();
+        for code in [1000, 1001, 1011, 3000, 4999] {
+            let code: Option<u16> = lua
+                .load(&format!("ws_message.close({code}, 'bye'):close_code()"))
+                .eval()
+                .unwrap();
+            assert!(code.is_some());
+        }
+        let code: Option<u16> = lua.load("ws_message.close():close_code()").eval().unwrap();
+        assert_eq!(code, None);
+        for code in [0, 999, 1005, 1006, 1015, 2000, 5000] {
+            let err = lua
+                .load(&format!("ws_message.close({code})"))
+                .exec()
+                .unwrap_err();
+            assert!(err.to_string().contains("Invalid Close Code"), "{code}");
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use super::ws::WSMessage;
+use crate::error::Error as WebError;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use http::{HeaderName, HeaderValue};
+use mlua::prelude::*;
+use std::{collections::HashMap, rc::Rc, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpStream,
+    sync::{watch, Mutex},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn client_closed() -> LuaError {
+    LuaError::ExternalError(Arc::new(WebError::new(5054, "websocket client is closed")))
+}
+
+// 重连时的等待时间从backoff开始每次翻倍，最多max_backoff秒，retries为最多重试次数
+#[derive(Clone, Copy)]
+struct Backoff {
+    retries: u32,
+    initial: Duration,
+    max: Duration,
+}
+
+// hive.ws_connect返回的连接对象，send和recv可以在不同的协程里同时使用
+#[derive(Clone)]
+pub struct WSClient {
+    url: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    backoff: Backoff,
+    sink: Rc<Mutex<Option<SplitSink<ClientStream, Message>>>>,
+    stream: Rc<Mutex<Option<SplitStream<ClientStream>>>>,
+    // 每次重连加1，等待中的recv放开stream的锁
+    reconnects: Rc<watch::Sender<u64>>,
+}
+
+impl WSClient {
+    async fn connect(&self) -> LuaResult<ClientStream> {
+        let mut request = self.url.as_str().into_client_request().to_lua_err()?;
+        for (key, val) in self.headers.iter() {
+            request.headers_mut().append(key, val.clone());
+        }
+        let (stream, _) = connect_async(request).await.to_lua_err()?;
+        Ok(stream)
+    }
+
+    // 先关闭旧连接，再按照backoff重试
+    async fn reconnect(&self) -> LuaResult<()> {
+        self.reconnects.send_modify(|n| *n += 1);
+        let mut sink = self.sink.lock().await;
+        let mut stream = self.stream.lock().await;
+        if let Some(mut old) = sink.take() {
+            let _ = old.close().await;
+        }
+        stream.take();
+        let mut delay = self.backoff.initial;
+        let mut attempt = 0;
+        loop {
+            match self.connect().await {
+                Ok(ws) => {
+                    let (new_sink, new_stream) = ws.split();
+                    *sink = Some(new_sink);
+                    *stream = Some(new_stream);
+                    return Ok(());
+                }
+                Err(err) => {
+                    attempt += 1;
+                    if attempt > self.backoff.retries {
+                        return Err(LuaError::ExternalError(Arc::new(WebError::new(
+                            5055,
+                            format!(
+                                "websocket reconnect to {} failed after {attempt} attempts: {err}",
+                                self.url
+                            ),
+                        ))));
+                    }
+                    log::warn!(
+                        "websocket reconnect to {} failed: {err}, retry in {:?}",
+                        self.url,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.backoff.max);
+                }
+            }
+        }
+    }
+}
+
+impl LuaUserData for WSClient {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_async_method("send", |_, this, msg: LuaAnyUserData| async move {
+            let msg = msg.take::<WSMessage>()?;
+            let mut sink = this.sink.lock().await;
+            let sink = sink.as_mut().ok_or_else(client_closed)?;
+            sink.send(msg.0).await.to_lua_err()
+        });
+        // 连接断开时返回nil，等待期间重连时在新的连接上继续等待
+        _methods.add_async_method("recv", |_, this, ()| async move {
+            loop {
+                let mut reconnected = this.reconnects.subscribe();
+                let mut stream = this.stream.lock().await;
+                let next = match stream.as_mut() {
+                    Some(stream) => tokio::select! {
+                        next = stream.next() => next,
+                        _ = reconnected.changed() => continue,
+                    },
+                    None => return Err(client_closed()),
+                };
+                return match next {
+                    Some(Ok(msg)) => Ok(Some(WSMessage(msg))),
+                    Some(Err(err)) => {
+                        stream.take();
+                        Err(err).to_lua_err()
+                    }
+                    None => {
+                        stream.take();
+                        Ok(None)
+                    }
+                };
+            }
+        });
+        _methods.add_async_method(
+            "close",
+            |_, this, (code, reason): (Option<u16>, Option<String>)| async move {
+                let sink = this.sink.lock().await.take();
+                if let Some(mut sink) = sink {
+                    let frame = CloseFrame {
+                        code: code.map(CloseCode::from).unwrap_or(CloseCode::Normal),
+                        reason: reason.unwrap_or_default().into(),
+                    };
+                    sink.send(Message::Close(Some(frame))).await.to_lua_err()?;
+                    sink.close().await.to_lua_err()?;
+                }
+                Ok(())
+            },
+        );
+        _methods.add_async_method(
+            "reconnect",
+            |_, this, ()| async move { this.reconnect().await },
+        );
+        _methods.add_method("url", |_, this, ()| Ok(this.url.clone()));
+    }
+}
+
+// hive.ws_connect(url, headers, options)
+// options: { retries = 5, backoff = 0.5, max_backoff = 30 }
+pub fn create_ws_connect(lua: &Lua) -> LuaResult<LuaFunction<'_>> {
+    lua.create_async_function(
+        |_,
+         (url, headers, options): (
+            String,
+            Option<HashMap<String, String>>,
+            Option<LuaTable>,
+        )| async move {
+            let mut header_list = Vec::new();
+            for (key, val) in headers.unwrap_or_default() {
+                header_list.push((
+                    HeaderName::from_bytes(key.as_bytes()).to_lua_err()?,
+                    HeaderValue::from_str(&val).to_lua_err()?,
+                ));
+            }
+            let mut backoff = Backoff {
+                retries: 5,
+                initial: Duration::from_millis(500),
+                max: Duration::from_secs(30),
+            };
+            if let Some(options) = options {
+                if let Some(retries) = options.get::<_, Option<u32>>("retries")? {
+                    backoff.retries = retries;
+                }
+                if let Some(initial) = options.get::<_, Option<f64>>("backoff")? {
+                    backoff.initial = Duration::from_secs_f64(initial.max(0.0));
+                }
+                if let Some(max) = options.get::<_, Option<f64>>("max_backoff")? {
+                    backoff.max = Duration::from_secs_f64(max.max(0.0));
+                }
+            }
+            let client = WSClient {
+                url,
+                headers: header_list,
+                backoff,
+                sink: Rc::new(Mutex::new(None)),
+                stream: Rc::new(Mutex::new(None)),
+                reconnects: Rc::new(watch::channel(0).0),
+            };
+            let (sink, stream) = client.connect().await?.split();
+            *client.sink.lock().await = Some(sink);
+            *client.stream.lock().await = Some(stream);
+            Ok(client)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::lua_request::LuaRequest;
+    use crate::lua::response::HiveResponse;
+    use crate::LocalExec;
+    use hyper::{server::conn::Http, service::service_fn, Body, Request};
+    use tokio::net::TcpListener;
+
+    // 握手由hive的req:upgrade处理
+    // 第一个连接不回复消息，模拟已经断开的连接，之后的连接原样返回收到的文本消息
+    const HANDLER: &str = r#"
+        local count = 0
+        return function(req)
+            count = count + 1
+            if count == 1 then
+                return req:upgrade(function() end)
+            end
+            return req:upgrade(function(peers, conn, msg)
+                if msg:is_text() then conn:send(msg) end
+            end)
+        end
+    "#;
+
+    async fn start_server() -> String {
+        let handler: LuaFunction = Lua::new().into_static().load(HANDLER).eval().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn_local(async move {
+            while let Ok((tcp, remote_addr)) = listener.accept().await {
+                let handler = handler.clone();
+                let service = service_fn(move |req: Request<Body>| {
+                    let handler = handler.clone();
+                    async move {
+                        let resp: LuaAnyUserData = handler
+                            .call_async(LuaRequest::new(req, remote_addr))
+                            .await?;
+                        Ok::<_, LuaError>(resp.take::<HiveResponse<Body>>()?.0)
+                    }
+                });
+                let conn = Http::new()
+                    .with_executor(LocalExec)
+                    .serve_connection(tcp, service)
+                    .with_upgrades();
+                tokio::task::spawn_local(conn);
+            }
+        });
+        format!("ws://{addr}")
+    }
+
+    #[tokio::test]
+    async fn reconnect_while_recv_is_pending() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let url = start_server().await;
+                let lua = Lua::new();
+                let connect = create_ws_connect(&lua).unwrap();
+                let client: LuaAnyUserData = connect.call_async(url).await.unwrap();
+                let method = |name: &str| -> LuaFunction {
+                    let code = format!("function(client, ...) return client:{name}(...) end");
+                    lua.load(&code).eval().unwrap()
+                };
+                let (recv, reconnect, send) = (method("recv"), method("reconnect"), method("send"));
+                let pending = recv.call_async::<_, Option<LuaAnyUserData>>(client.clone());
+                let reconnect_and_send = async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    reconnect.call_async::<_, ()>(client.clone()).await?;
+                    let msg = WSMessage(Message::Text("hello".to_string()));
+                    send.call_async::<_, ()>((client.clone(), msg)).await
+                };
+                let (received, sent) = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    futures_util::future::join(pending, reconnect_and_send),
+                )
+                .await
+                .expect("reconnect blocked by a pending recv");
+                sent.unwrap();
+                let msg = received.unwrap().expect("connection closed");
+                assert_eq!(
+                    msg.borrow::<WSMessage>().unwrap().0,
+                    Message::Text("hello".to_string())
+                );
+            })
+            .await;
+    }
+}
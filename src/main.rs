@@ -87,6 +87,15 @@ pub struct Args {
     /// 设置监视路径，默认当前路径
     #[arg(short, long, default_value = ".")]
     watch_dir: String,
+    /// 热更新监视的文件，多个glob之间用“,”分割，默认监视所有lua文件，例如：controllers/**/*.lua
+    #[arg(long, value_delimiter = ',')]
+    watch_include: Vec<String>,
+    /// 热更新忽略的文件，多个glob之间用“,”分割，.gitignore中的文件默认忽略，例如：tests/**
+    #[arg(long, value_delimiter = ',')]
+    watch_exclude: Vec<String>,
+    /// 热更新的防抖时间，单位毫秒，默认值：200
+    #[arg(long, default_value_t = 200)]
+    watch_debounce: u64,
     /// 创建项目，举例：hive --create test
     #[arg(long)]
     create: Option<String>,
@@ -108,6 +117,7 @@ pub struct Args {
     feature = "luajit52"
 ))]
 async fn lua_run(args: Args) -> WebResult<()> {
+    use crate::lua::cors::Cors;
     use crate::lua::hive_func::add_hive_func;
     #[cfg(not(feature = "lua_hotfix"))]
     use crate::lua::router::HiveRouter;
@@ -132,6 +142,11 @@ async fn lua_run(args: Args) -> WebResult<()> {
     let is_ipv4: bool = handler.get("is_ipv4").unwrap_or(true);
     let localhost: String = handler.get("addr").unwrap_or("127.0.0.1".to_owned());
     let port: u16 = handler.get("port").unwrap_or(3000);
+    let etag: bool = handler.get("etag").unwrap_or(false);
+    #[cfg(feature = "lua_hotfix")]
+    let livereload: bool = handler.get("livereload").unwrap_or(false);
+    let cors: Option<LuaTable> = handler.get("cors")?;
+    let cors: Option<Arc<Cors>> = cors.map(|t| Cors::new(&lua, t)).transpose()?.map(Arc::new);
     let addr: SocketAddr = if is_ipv4 {
         SocketAddr::new(IpAddr::V4(localhost.parse()?), port)
     } else {
@@ -148,6 +163,11 @@ async fn lua_run(args: Args) -> WebResult<()> {
             handler: Some(http_handler),
             exception,
             router,
+            etag,
+            cors,
+            dev: args.dev,
+            #[cfg(feature = "lua_hotfix")]
+            livereload: args.dev && livereload,
         };
         let server = Server::bind(&addr).executor(LocalExec).serve(make_svc);
         let local = tokio::task::LocalSet::new();
@@ -169,6 +189,11 @@ async fn lua_run(args: Args) -> WebResult<()> {
             handler: Some(http_handler),
             exception,
             router,
+            etag,
+            cors,
+            dev: args.dev,
+            #[cfg(feature = "lua_hotfix")]
+            livereload: args.dev && livereload,
         };
         let server = Server::bind(&addr).executor(LocalExec).serve(make_svc);
         let local = tokio::task::LocalSet::new();